@@ -9,7 +9,7 @@ local diff = 0.0001
 local visc = 0.0001
 local iter = 10
 
-local win = egui.create_window("CFD", N * SCALE, N * SCALE)
+local win = egui.create_window("CFD", N, N, {scale = SCALE})
 
 local function IX(x, y)
     return x + y * SIZE
@@ -162,25 +162,31 @@ end
 -- 描画
 -------------------------------------------------
 local function draw()
-    win:cls(0, 0, 0)
-
     for j = 1, N do
         for i = 1, N do
             local d = density[IX(i, j)]
             if d > 255 then d = 255 end
             if d < 0 then d = 0 end
 
-            win:fillrect(
-                (i - 1) * SCALE,
-                (j - 1) * SCALE,
-                i * SCALE,
-                j * SCALE,
-                d, d, d
-            )
+            -- 拡大表示はウィンドウのscaleで行う
+            win:point(i - 1, j - 1, d, d, d)
         end
     end
 end
 
+-------------------------------------------------
+-- パラメータ調整UI
+-------------------------------------------------
+win:onui(function(ui)
+    dt = ui:slider("dt", dt, 0.01, 0.5)
+    ui:collapsing("viscosity / diffusion", function(ui)
+        visc = ui:slider("visc x1e-4", visc * 10000, 0, 10) / 10000
+        diff = ui:slider("diff x1e-4", diff * 10000, 0, 10) / 10000
+    end)
+    iter = ui:slider("iter", iter, 1, 40, 1)
+    if ui:button("clear") then init() end
+end)
+
 -------------------------------------------------
 -- 入力
 -------------------------------------------------
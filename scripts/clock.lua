@@ -1,10 +1,8 @@
-local fpswaiter = require"scripts.mod.fpswaiter"
-local waiter = fpswaiter.fpswaiter:new()
-
 local function analog_clock()
     local cx, cy = 200, 200 -- center of clock
     local radius = 150
     local w = egui.create_window("clock", (radius * 2 - cx / 2) * 2, (radius * 2 - cy / 2) * 2)
+    w:setantialias(true)
 
     while true do
         w:cls()
@@ -21,6 +19,7 @@ local function analog_clock()
                 x1 = cx + math.cos(angle) * (radius - 20)
                 y1 = cx + math.sin(angle) * (radius - 20)
             end
+            w:setlinewidth(i % 5 == 0 and 3 or 1)
             w:line(x1, y1, x2, y2, r, g, b)
         end
 
@@ -34,19 +33,23 @@ local function analog_clock()
         local hour_angle = math.rad((hour) * 30 - 90)
         local hx = cx + math.cos(hour_angle) * (radius * 0.5)
         local hy = cy + math.sin(hour_angle) * (radius * 0.5)
+        w:setlinewidth(8)
         w:line(cx, cy, hx, hy, 0.2 * 255, 0.2 * 255, 0.6 * 255)
 
         -- Draw minute hand
         local min_angle = math.rad((min) * 6 - 90)
         local mx = cx + math.cos(min_angle) * (radius * 0.8)
         local my = cy + math.sin(min_angle) * (radius * 0.8)
+        w:setlinewidth(5)
         w:line(cx, cy, mx, my, 0.2 * 255, 0.6 * 255, 0.2 * 255)
 
         -- Draw second hand (smooth)
         local sec_angle = math.rad(sec * 6 - 90)
         local sx = cx + math.cos(sec_angle) * (radius * 0.9)
         local sy = cy + math.sin(sec_angle) * (radius * 0.9)
+        w:setlinewidth(2)
         w:line(cx, cy, sx, sy, 0.8 * 255, 0.1 * 255, 0.1 * 255)
+        w:fillcircle(cx, cy, 6, 0.8 * 255, 0.1 * 255, 0.1 * 255)
 
         coroutine.yield()
     end
@@ -71,11 +74,11 @@ end
 local threads = {}
 table.insert(threads, coroutine.wrap(analog_clock))
 table.insert(threads, coroutine.wrap(digital_clock))
+-- アイドルモード: sleepの期限か入力があったときだけ進める（CPUをほとんど使わない）
+egui.setidle(true)
 while true do
     for _, thf in ipairs(threads) do
         thf()
     end
-    -- sleep(10)
-    coroutine.yield()
-    waiter:await(60)
+    sleep(1 / 20)
 end
@@ -126,8 +126,9 @@ while true do
     -- ドロネー計算
     local tris = delaunay(points)
 
-    -- 線描画
-    for _, tri in ipairs(tris) do
+    -- 塗りつぶし・線描画
+    for i, tri in ipairs(tris) do
+        win:filltriangle(tri[1].x, tri[1].y, tri[2].x, tri[2].y, tri[3].x, tri[3].y, 0, 32 + (i * 37) % 96, 0)
         win:line(tri[1].x, tri[1].y, tri[2].x, tri[2].y, 0, 255, 0)
         win:line(tri[2].x, tri[2].y, tri[3].x, tri[3].y, 0, 255, 0)
         win:line(tri[3].x, tri[3].y, tri[1].x, tri[1].y, 0, 255, 0)
@@ -1,5 +1,6 @@
 local width, height = 600, 600
 local w = egui.create_window("Droplets", width, height)
+w:setblend("add") -- 重なった波紋が明るくなる
 local rnd = function (r, lb)
     return math.random() * r + (lb or 0)
 end
@@ -28,6 +28,7 @@ end
 ------------------------------------------------------------
 local WIDTH          = 600 -- 256
 local HEIGHT         = 600 -- 224
+local HUD_HEIGHT     = 18  -- 上部のスコア表示の高さ
 
 local win            = egui.create_window("GRADIUS", WIDTH, HEIGHT)
 
@@ -293,6 +294,8 @@ end
 -- 描画処理
 ------------------------------------------------------------
 local function draw()
+    -- プレイフィールドはHUDの下だけに描く
+    win:setclip(0, HUD_HEIGHT, WIDTH, HEIGHT - HUD_HEIGHT)
     win:cls(0, 0, 20)
 
     -- 星背景
@@ -327,7 +330,8 @@ local function draw()
     end
 
     -- UI
-    win:fillrect(0, 0, WIDTH, 12, 0, 0, 0)
+    win:resetclip()
+    win:fillrect(0, 0, WIDTH - 1, HUD_HEIGHT - 1, 0, 0, 0)
     win:text(0, 0, string.format("SCORE: %d", score))
     win:text(120, 0, string.format("N-WAY: %d", math.max(1, math.floor(score/1000)+1)))
 end
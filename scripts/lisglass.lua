@@ -2,6 +2,7 @@ print("hello")
 w = egui.create_window("TestWin",600,600)
 print("HERE")
 w:cls(0,0,0)
+w:setblend("add") -- 線が重なるところを光らせる
 local i = 0
 local rnd = function (n, l) return math.random() * (n or 100) + (l or 0) end
 local lissajous = {
@@ -20,7 +21,7 @@ local lissajous = {
             local y1 = math.cos(ang * r1) * 300 + 300
             local x2 = math.sin(ang * r2) * 300 + 300
             local y2 = math.cos(ang * r2) * 300 + 300
-            window:line(x1, y1, x2, y2, r, g, b)
+            window:line(x1, y1, x2, y2, r, g, b, 96)
         end
         self.t = self.t + 1
     end
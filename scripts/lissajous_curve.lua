@@ -1,6 +1,7 @@
 local w = arg[1] or 600
 local h = arg[2] or 600
 local win = egui.create_window("lissajous curve", w, h)
+win:setantialias(true)
 local rnd = function (r, l) return math.random() * r + (l or 0) end
 
 local obj = {
@@ -16,7 +17,11 @@ local obj = {
             local t = ((self.i + p / steps) / steps) * 2 * math.pi
             local x = self.A * math.sin(self.a * t + self.delta)
             local y = self.B * math.sin(self.b * t)
-            win:point(w / 2 + x, h / 2 + y, 255, 255, 255)
+            -- 前の点と線で結ぶ（点だけだと速く動く部分で途切れる）
+            if self.px then
+                win:line(self.px, self.py, w / 2 + x, h / 2 + y, 255, 255, 255)
+            end
+            self.px, self.py = w / 2 + x, h / 2 + y
         end
         self.i = self.i + 1
     end
@@ -1,9 +1,11 @@
 local module = {}
 
+-- フレームレート制御は本体(egui.setfps)で行う
+-- 以前はhpc()とsleepでビジーウェイトしていたが、CPUを消費するためネイティブ実装に置き換え
 local fpswaiter = {}
 function fpswaiter:new()
     local o = {
-        last = hpc(),
+        fps = nil,
     }
     setmetatable(o, self)
     self.__index = self
@@ -11,12 +13,11 @@ function fpswaiter:new()
 end
 
 function fpswaiter:await(fps)
-    local secs = 1 / fps
-    while hpc() - self.last < secs do
-        sleep(0.0001)
+    if self.fps ~= fps then
+        egui.setfps(fps)
+        self.fps = fps
     end
-    self.last = hpc()
 end
 
 module.fpswaiter = fpswaiter
-return module
\ No newline at end of file
+return module
@@ -0,0 +1,74 @@
+-- test_dropfile.lua: ファイルのドラッグ&ドロップ / ホイール / フォーカスのテスト
+-- .mid をドロップすると midiplayer で再生、画像をドロップすると image.load で表示
+local midiplayer = require"scripts.mod.midiplayer"
+
+local width, height = 600, 400
+local win = egui.create_window("test dropfile", width, height)
+
+-- MIDIが使えない環境でも画像の表示はできるようにする
+local midiout = nil
+local ok, ports = pcall(midi.midiout)
+for _, port in ipairs(ok and ports or {}) do
+    midiout = midi.openoutput(port)
+end
+
+local player = nil
+local img = nil
+local ox, oy = 0, 0
+local status = "drop .mid or image file here"
+local hovering = nil
+local focused = true
+
+local function load_file(path)
+    local ext = (path:match("%.([^.\\/]+)$") or ""):lower()
+    if ext == "mid" or ext == "midi" then
+        local ok, p = pcall(midiplayer.new, midiplayer, path)
+        if ok then
+            player = p
+            status = "playing " .. path
+        else
+            status = "midi error: " .. tostring(p)
+        end
+    else
+        local ok, i = pcall(image.load, path)
+        if ok then
+            img = i
+            ox, oy = 0, 0
+            status = "image " .. path
+        else
+            status = "image error: " .. tostring(i)
+        end
+    end
+end
+
+while true do
+    for _, ev in ipairs(egui.pollevents()) do
+        if ev.type == "filedrop" then
+            hovering = nil
+            load_file(ev.path)
+        elseif ev.type == "filehover" then
+            hovering = ev.path
+        elseif ev.type == "filehovercancel" then
+            hovering = nil
+        elseif ev.type == "wheel" then
+            -- ホイールで画像をスクロール（行単位なら20px/行）
+            local scale = ev.unit == "line" and 20 or 1
+            ox = ox + ev.dx * scale
+            oy = oy + ev.dy * scale
+        elseif ev.type == "focus" then
+            focused = ev.focused
+        end
+    end
+    if player then
+        player:play(midiout)
+    end
+
+    win:cls(0, 0, 0)
+    if img then
+        win:drawimage(img, math.floor(ox), math.floor(oy))
+    end
+    win:fillrect(0, height - 40, width, height, 0, 0, 64)
+    win:text(0, height - 40, hovering and ("drop: " .. hovering) or status)
+    win:text(0, height - 20, focused and "focused" or "unfocused")
+    coroutine.yield()
+end
@@ -0,0 +1,48 @@
+-- test_pollevents.lua: egui.pollevents / egui.iskeydown / egui.mousepos / egui.mousebuttons のテスト
+local width, height = 600, 400
+local win = egui.create_window("test pollevents", width, height)
+local x, y = width / 2, height / 2
+local typed = ""
+egui.setime(true)
+
+local function modnames(ev)
+    local names = {}
+    for _, name in ipairs({"shift", "ctrl", "alt", "super"}) do
+        if ev[name] then names[#names + 1] = name end
+    end
+    return table.concat(names, "+")
+end
+
+while true do
+    for _, ev in ipairs(egui.pollevents()) do
+        if ev.type == "key" then
+            win:scroll(0, win:gettextfontsize())
+            win:text(0, 0, "KEY " .. ev.state .. " " .. tostring(ev.key) .. " " .. ev.scancode
+                .. (ev.repeated and " repeat" or "") .. " " .. modnames(ev))
+            if ev.state == "pressed" and ev.key == "Back" then
+                typed = typed:gsub("[%z\1-\127\194-\244][\128-\191]*$", "")
+            end
+        elseif ev.type == "text" then
+            typed = typed .. ev.text
+        elseif ev.type == "ime" then
+            win:scroll(0, win:gettextfontsize())
+            win:text(0, 0, "IME " .. ev.kind .. " " .. ev.text)
+            if ev.kind == "commit" then typed = typed .. ev.text end
+        elseif ev.type == "mousebutton" then
+            win:scroll(0, win:gettextfontsize())
+            win:text(0, 0, "MOUSE " .. ev.state .. " " .. ev.button)
+        end
+    end
+    -- 押しっぱなしの判定はキー状態で行う（keystatesテーブル不要）
+    win:fillrect(x - 5, y - 5, x + 5, y + 5, 0, 0, 0)
+    if egui.iskeydown("Left") then x = x - 2 end
+    if egui.iskeydown("Right") then x = x + 2 end
+    if egui.iskeydown("Up") then y = y - 2 end
+    if egui.iskeydown("Down") then y = y + 2 end
+    local l, r, m = egui.mousebuttons()
+    win:fillrect(x - 5, y - 5, x + 5, y + 5, l and 255 or 0, r and 255 or 0, m and 255 or 255)
+    local mx, my = egui.mousepos()
+    win:fillrect(0, height - 20, width, height, 0, 0, 0)
+    win:text(0, height - 20, string.format("mouse %d, %d  text: %s", mx, my, typed))
+    coroutine.yield()
+end
@@ -0,0 +1,35 @@
+-- test_task.lua: task.spawn / task.wait / task.wait_frames / task.cancel のテスト
+local w = egui.create_window("task test", 320, 240)
+
+local blinker = task.spawn("blinker", function()
+    while true do
+        w:fillrect(10, 10, 40, 40, 255, 0, 0)
+        task.wait(0.5)
+        w:fillrect(10, 10, 40, 40, 0, 0, 0)
+        task.wait(0.5)
+    end
+end)
+
+task.spawn("counter", function(step)
+    local n = 0
+    while true do
+        w:fillrect(60, 10, 200, 30, 0, 0, 0)
+        w:text(60, 10, "frames: " .. n)
+        n = n + step
+        task.wait_frames(10)
+    end
+end, 10)
+
+task.spawn("crasher", function()
+    task.wait(1.0)
+    error("this task fails, others keep running")
+end)
+
+local t0 = hpc()
+while true do
+    if blinker:status() ~= "cancelled" and hpc() - t0 > 3.0 then
+        blinker:cancel()
+        print("blinker:", blinker:status(), "tasks:", task.count())
+    end
+    coroutine.yield()
+end
@@ -22,12 +22,22 @@ function egui.mousehandler(state, button)
 end
 
 function egui.cursorhandler(x, y)
-    local wx, wy = win:getx(), win:gety()
-    local px, py = x - wx, y - wy
-    table.insert(printouts, "CURSOR " .. px .. " " .. py)
-    print("CURSOR", px, py)
+    -- キャンバス上の座標は win:mousepos() で取得（x, yは親ウィンドウの物理座標）
+    local px, py = win:mousepos()
+    if px and win:ishovered() then
+        table.insert(printouts, "CURSOR " .. px .. " " .. py)
+        print("CURSOR", px, py)
+    end
 end
 
+win:onclick(function(x, y, button, double)
+    table.insert(printouts, "CLICK " .. button .. " " .. x .. " " .. y .. (double and " (double)" or ""))
+end)
+
+win:ondrag(function(x, y, dx, dy, button, phase)
+    table.insert(printouts, string.format("DRAG %s %s %d %d (%.1f, %.1f)", phase, button, x, y, dx, dy))
+end)
+
 while true do
     for i = #printouts, 1, -1 do
         win:scroll(0, win:gettextfontsize())
@@ -0,0 +1,2094 @@
+//! LuaWindow と graphicバッファ(RGBABufferBase)で共通の描画処理
+//! - Canvas トレイトを実装すると描画メソッドが使えるようになる
+//! - add_canvas_methods でLuaのメソッドを登録するので、両者のLua APIは同じになる
+//!
+//! # 座標・色の規則
+//! - rect/fillrect は左上・右下の角を指定する (x1, y1, x2, y2)、両端を含む
+//! - 色を省略したときは 255（アルファも255）
+//! - paint は塗ったピクセル数を返す
+//! - 互換モード (setcompat(true)) では以前のgraphicバッファの規則になる（graphicバッファの既定値）
+//!   - rect/fillrect は (x, y, width, height)、省略した色は 0、paint は何も返さない
+
+use crate::luafont::{ellipsize, get_font, get_fonts, layout_line, line_metrics, wrap_text, FontSpec, PlacedGlyph, Wrap, DEFAULT_FONT_PATH};
+use crate::luaimage::LuaImage;
+use mlua::{UserDataMethods, Variadic};
+use std::sync::Mutex;
+
+pub type Rgba = (u8, u8, u8, u8);
+
+// クリップ矩形 (x0, y0, x1, y1)（x1/y1は含まない）
+pub type ClipRect = (i32, i32, i32, i32);
+
+// push で保存できる数（popし忘れでメモリを使い続けないように）
+const MAX_SAVED_STATES: usize = 256;
+
+// 描画の設定と作業領域（キャンバスごと）
+#[derive(Clone, Debug)]
+pub struct CanvasState {
+    pub text_color: Rgba,
+    pub text_font_size: usize,
+    pub fontpath: String,
+    pub fontindex: u32, // フォントコレクション (.ttc) の中の番号
+    pub fallback_fonts: Vec<FontSpec>, // 主フォントにない文字を探すフォント（順に探す）
+    pub compat: bool, // 以前のgraphicバッファの引数・戻り値の規則
+    pub line_width: f64, // line/circle/rect/triangle/polygonの線の太さ
+    pub antialias: bool, // trueならline/circle/fillcircleをアンチエイリアスで描く
+    pub blend: BlendMode, // 描画したピクセルと元のピクセルの合成方法
+    pub transform: Transform, // 描画する座標に掛ける変換 (translate/scale/rotate)
+    pub clip: Option<ClipRect>, // 描画できる範囲（変換の影響は受けない）
+    pub saved: Vec<(Transform, Option<ClipRect>)>, // push で保存した変換とクリップ
+    pub fillpaint_stack: Vec<(i32, i32)>,
+    pub fillpaint_visited: Vec<bool>,
+}
+
+impl CanvasState {
+    pub fn new(compat: bool) -> Self {
+        Self {
+            text_color: (255, 255, 255, 255),
+            text_font_size: 16, // デフォルトサイズ
+            fontpath: DEFAULT_FONT_PATH.to_string(),
+            fontindex: 0,
+            fallback_fonts: Vec::new(),
+            compat,
+            line_width: 1.0,
+            antialias: false,
+            blend: BlendMode::Alpha,
+            transform: Transform::IDENTITY,
+            clip: None,
+            saved: Vec::new(),
+            fillpaint_stack: Vec::new(),
+            fillpaint_visited: Vec::new(),
+        }
+    }
+}
+
+// dst(RGBA)にsrcをアルファブレンド (整数演算)
+// out_a = src_a + dst_a * (255 - src_a) / 255
+#[inline(always)]
+pub fn blend_pixel(dst: &mut [u8], r: u8, g: u8, b: u8, src_a: i32) {
+    let dst_r = dst[0] as i32;
+    let dst_g = dst[1] as i32;
+    let dst_b = dst[2] as i32;
+    let dst_a = dst[3] as i32;
+    let out_a = src_a + ((dst_a * (255 - src_a)) / 255);
+    if out_a > 0 {
+        dst[0] = ((r as i32 * src_a + dst_r * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
+        dst[1] = ((g as i32 * src_a + dst_g * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
+        dst[2] = ((b as i32 * src_a + dst_b * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
+        dst[3] = out_a.min(255) as u8;
+    } else {
+        dst[..4].fill(0);
+    }
+}
+
+// 描画の合成方法（clear/scroll以外のすべての描画に使う）
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    Replace,  // ブレンドせずに上書き（アルファもそのまま書き込む）
+    Alpha,    // アルファブレンド（デフォルト）
+    Add,      // 加算
+    Subtract, // 減算（元の色 - 描く色）
+    Multiply, // 乗算
+    Screen,   // スクリーン
+    Xor,      // 排他的論理和
+    Min,      // 比較（暗）
+    Max,      // 比較（明）
+}
+
+impl BlendMode {
+    const NAMES: [(&'static str, BlendMode); 9] = [
+        ("replace", BlendMode::Replace),
+        ("alpha", BlendMode::Alpha),
+        ("add", BlendMode::Add),
+        ("subtract", BlendMode::Subtract),
+        ("multiply", BlendMode::Multiply),
+        ("screen", BlendMode::Screen),
+        ("xor", BlendMode::Xor),
+        ("min", BlendMode::Min),
+        ("max", BlendMode::Max),
+    ];
+
+    pub fn from_name(name: &str) -> mlua::Result<Self> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, mode)| *mode).ok_or_else(|| {
+            let names = Self::NAMES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ");
+            mlua::Error::external(format!("unknown blend mode: {} ({})", name, names))
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(_, mode)| *mode == self).map(|(n, _)| *n).unwrap_or("alpha")
+    }
+}
+
+// modeに従ってdst(RGBA)にsrcを合成する
+// alpha/replace以外は、合成した色をsrc_aの割合で元の色に混ぜる（アルファはアルファブレンドと同じ）
+#[inline(always)]
+pub fn blend_with(mode: BlendMode, dst: &mut [u8], r: u8, g: u8, b: u8, src_a: i32) {
+    let f: fn(i32, i32) -> i32 = match mode {
+        BlendMode::Replace => {
+            dst[..4].copy_from_slice(&[r, g, b, src_a.clamp(0, 255) as u8]);
+            return;
+        }
+        BlendMode::Alpha => {
+            blend_pixel(dst, r, g, b, src_a);
+            return;
+        }
+        BlendMode::Add => |s, d| (s + d).min(255),
+        BlendMode::Subtract => |s, d| (d - s).max(0),
+        BlendMode::Multiply => |s, d| s * d / 255,
+        BlendMode::Screen => |s, d| 255 - (255 - s) * (255 - d) / 255,
+        BlendMode::Xor => |s, d| s ^ d,
+        BlendMode::Min => |s, d| s.min(d),
+        BlendMode::Max => |s, d| s.max(d),
+    };
+    for (i, s) in [r, g, b].into_iter().enumerate() {
+        let d = dst[i] as i32;
+        dst[i] = (d + (f(s as i32, d) - d) * src_a / 255).clamp(0, 255) as u8;
+    }
+    let dst_a = dst[3] as i32;
+    dst[3] = (src_a + dst_a * (255 - src_a) / 255).min(255) as u8;
+}
+
+// blit のオプション {blend = bool, colorkey = {r, g, b}, opacity = 0.0〜1.0}
+#[derive(Clone, Copy, Debug)]
+pub struct BlitOptions {
+    pub blend: bool,                   // trueならキャンバスの合成方法 (setblend) で合成、falseならそのまま上書き
+    pub colorkey: Option<(u8, u8, u8)>, // この色のピクセルは転送しない
+    pub opacity: u8,                   // 転送元のアルファに掛ける (0〜255)
+}
+
+impl Default for BlitOptions {
+    fn default() -> Self {
+        Self { blend: true, colorkey: None, opacity: 255 }
+    }
+}
+
+impl BlitOptions {
+    pub fn from_table(table: &mlua::Table) -> mlua::Result<Self> {
+        let mut opts = Self::default();
+        if let Some(blend) = table.get::<_, Option<bool>>("blend")? {
+            opts.blend = blend;
+        }
+        if let Some(key) = table.get::<_, Option<mlua::Table>>("colorkey")? {
+            opts.colorkey = Some((key.get(1)?, key.get(2)?, key.get(3)?));
+        }
+        if let Some(opacity) = table.get::<_, Option<f64>>("opacity")? {
+            opts.opacity = (opacity.clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+        Ok(opts)
+    }
+}
+
+// drawimageex のオプション {angle, sx, sy, ox, oy, flipx, flipy, alpha, filter, src = {x, y, w, h}}
+#[derive(Clone, Copy, Debug)]
+pub struct DrawImageOptions {
+    pub angle: f64,  // 回転角度（ラジアン、時計回り）
+    pub sx: f64,     // 拡大率
+    pub sy: f64,
+    pub ox: f64,     // 回転・拡大の中心（画像の左上からの位置）、(x, y) に置かれる
+    pub oy: f64,
+    pub flipx: bool, // 左右反転
+    pub flipy: bool, // 上下反転
+    pub alpha: u8,   // 転送元のアルファに掛ける (0〜255)
+    pub linear: bool, // trueならバイリニア補間、falseなら最近傍
+    pub src: Option<(i32, i32, Option<i32>, Option<i32>)>, // 転送元の範囲 (x, y, width, height)
+}
+
+impl Default for DrawImageOptions {
+    fn default() -> Self {
+        Self { angle: 0.0, sx: 1.0, sy: 1.0, ox: 0.0, oy: 0.0, flipx: false, flipy: false, alpha: 255, linear: false, src: None }
+    }
+}
+
+impl DrawImageOptions {
+    pub fn from_table(table: &mlua::Table) -> mlua::Result<Self> {
+        let mut opts = Self::default();
+        opts.angle = table.get::<_, Option<f64>>("angle")?.unwrap_or(opts.angle);
+        opts.sx = table.get::<_, Option<f64>>("sx")?.unwrap_or(opts.sx);
+        // syを省略するとsxと同じ
+        opts.sy = table.get::<_, Option<f64>>("sy")?.unwrap_or(opts.sx);
+        opts.ox = table.get::<_, Option<f64>>("ox")?.unwrap_or(opts.ox);
+        opts.oy = table.get::<_, Option<f64>>("oy")?.unwrap_or(opts.oy);
+        opts.flipx = table.get::<_, Option<bool>>("flipx")?.unwrap_or(opts.flipx);
+        opts.flipy = table.get::<_, Option<bool>>("flipy")?.unwrap_or(opts.flipy);
+        if let Some(alpha) = table.get::<_, Option<f64>>("alpha")? {
+            opts.alpha = (alpha.clamp(0.0, 1.0) * 255.0).round() as u8;
+        }
+        if let Some(filter) = table.get::<_, Option<String>>("filter")? {
+            opts.linear = match filter.as_str() {
+                "nearest" => false,
+                "linear" | "bilinear" => true,
+                _ => return Err(mlua::Error::external(format!("unknown filter: {} (nearest or linear)", filter))),
+            };
+        }
+        if let Some(src) = table.get::<_, Option<mlua::Table>>("src")? {
+            opts.src = Some((src.get::<_, Option<i32>>(1)?.unwrap_or(0), src.get::<_, Option<i32>>(2)?.unwrap_or(0), src.get(3)?, src.get(4)?));
+        }
+        Ok(opts)
+    }
+}
+
+// textbox のオプション {align, valign, wrap, linespacing, ellipsis}
+#[derive(Clone, Copy, Debug)]
+pub struct TextBoxOptions {
+    pub align: f32,       // 0.0 = 左寄せ, 0.5 = 中央, 1.0 = 右寄せ
+    pub valign: f32,      // 0.0 = 上, 0.5 = 中央, 1.0 = 下
+    pub wrap: Wrap,
+    pub linespacing: f32, // 行の高さに掛ける行送り
+    pub ellipsis: bool,   // 入りきらない行の末尾を「…」にする
+}
+
+impl Default for TextBoxOptions {
+    fn default() -> Self {
+        Self { align: 0.0, valign: 0.0, wrap: Wrap::Word, linespacing: 1.0, ellipsis: true }
+    }
+}
+
+impl TextBoxOptions {
+    pub fn from_table(table: &mlua::Table) -> mlua::Result<Self> {
+        let mut opts = Self::default();
+        if let Some(align) = table.get::<_, Option<String>>("align")? {
+            opts.align = match align.as_str() {
+                "left" => 0.0,
+                "center" => 0.5,
+                "right" => 1.0,
+                _ => return Err(mlua::Error::external(format!("unknown align: {} (left, center or right)", align))),
+            };
+        }
+        if let Some(valign) = table.get::<_, Option<String>>("valign")? {
+            opts.valign = match valign.as_str() {
+                "top" => 0.0,
+                "middle" => 0.5,
+                "bottom" => 1.0,
+                _ => return Err(mlua::Error::external(format!("unknown valign: {} (top, middle or bottom)", valign))),
+            };
+        }
+        if let Some(wrap) = table.get::<_, Option<String>>("wrap")? {
+            opts.wrap = match wrap.as_str() {
+                "word" => Wrap::Word,
+                "char" => Wrap::Char,
+                "none" => Wrap::None,
+                _ => return Err(mlua::Error::external(format!("unknown wrap: {} (word, char or none)", wrap))),
+            };
+        }
+        if let Some(linespacing) = table.get::<_, Option<f32>>("linespacing")? {
+            if !linespacing.is_finite() || linespacing <= 0.0 {
+                return Err(mlua::Error::external("linespacing must be a positive finite number"));
+            }
+            opts.linespacing = linespacing;
+        }
+        opts.ellipsis = table.get::<_, Option<bool>>("ellipsis")?.unwrap_or(opts.ellipsis);
+        Ok(opts)
+    }
+}
+
+// blitの転送元から切り出したピクセル（転送元の範囲外は除いてある）
+pub struct PixelRegion {
+    pub x: i32, // 転送元での位置
+    pub y: i32,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>, // RGBA
+}
+
+impl PixelRegion {
+    // 画像の (x, y, width, height) の範囲を画像内にクリップして切り出す（width, height を省略すると右下まで）
+    pub fn from_image(img: &image::DynamicImage, x: i32, y: i32, width: Option<i32>, height: Option<i32>) -> Self {
+        let (iw, ih) = (img.width() as i32, img.height() as i32);
+        let x0 = x.clamp(0, iw);
+        let y0 = y.clamp(0, ih);
+        let x1 = width.map_or(iw, |w| x + w).clamp(x0, iw);
+        let y1 = height.map_or(ih, |h| y + h).clamp(y0, ih);
+        let sub = img.crop_imm(x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32).to_rgba8();
+        PixelRegion { x: x0, y: y0, width: sub.width() as usize, height: sub.height() as usize, data: sub.into_raw() }
+    }
+
+    // (u, v)（ピクセルの左上が整数）を含むピクセルの色
+    #[inline(always)]
+    pub fn sample_nearest(&self, u: f64, v: f64) -> Rgba {
+        let x = (u as usize).min(self.width - 1);
+        let y = (v as usize).min(self.height - 1);
+        let idx = (y * self.width + x) * 4;
+        (self.data[idx], self.data[idx + 1], self.data[idx + 2], self.data[idx + 3])
+    }
+
+    // (u, v) の色を周りの4ピクセルから補間する（端のピクセルは外側に延ばす）
+    // 透明なピクセルの色が混ざらないように、アルファを掛けた色で補間する
+    pub fn sample_linear(&self, u: f64, v: f64) -> Rgba {
+        let (fx, fy) = (u - 0.5, v - 0.5);
+        let (x0, y0) = (fx.floor(), fy.floor());
+        let (tx, ty) = (fx - x0, fy - y0);
+        let clamp_x = |x: f64| (x as i64).clamp(0, self.width as i64 - 1) as usize;
+        let clamp_y = |y: f64| (y as i64).clamp(0, self.height as i64 - 1) as usize;
+        let mut sum = [0.0; 4];
+        for (x, y, weight) in [
+            (x0, y0, (1.0 - tx) * (1.0 - ty)),
+            (x0 + 1.0, y0, tx * (1.0 - ty)),
+            (x0, y0 + 1.0, (1.0 - tx) * ty),
+            (x0 + 1.0, y0 + 1.0, tx * ty),
+        ] {
+            let idx = (clamp_y(y) * self.width + clamp_x(x)) * 4;
+            let a = self.data[idx + 3] as f64 * weight;
+            sum[0] += self.data[idx] as f64 * a;
+            sum[1] += self.data[idx + 1] as f64 * a;
+            sum[2] += self.data[idx + 2] as f64 * a;
+            sum[3] += a;
+        }
+        if sum[3] <= 0.0 {
+            return (0, 0, 0, 0);
+        }
+        let channel = |c: f64| (c / sum[3]).round().clamp(0.0, 255.0) as u8;
+        (channel(sum[0]), channel(sum[1]), channel(sum[2]), sum[3].round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+// アフィン変換 (x, y) -> (a * x + c * y + e, b * x + d * y + f)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+    pub e: f64,
+    pub f: f64,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform { a: 1.0, b: 0.0, c: 0.0, d: 1.0, e: 0.0, f: 0.0 };
+
+    pub fn translate(dx: f64, dy: f64) -> Self {
+        Transform { e: dx, f: dy, ..Self::IDENTITY }
+    }
+
+    pub fn scale(sx: f64, sy: f64) -> Self {
+        Transform { a: sx, d: sy, ..Self::IDENTITY }
+    }
+
+    // y軸が下向きなので、正の角度で時計回り（arc/pieと同じ向き）
+    pub fn rotate(angle: f64) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        Transform { a: cos, b: sin, c: -sin, d: cos, e: 0.0, f: 0.0 }
+    }
+
+    // other を適用してから self を適用する変換
+    pub fn multiply(self, other: Transform) -> Self {
+        Transform {
+            a: self.a * other.a + self.c * other.b,
+            b: self.b * other.a + self.d * other.b,
+            c: self.a * other.c + self.c * other.d,
+            d: self.b * other.c + self.d * other.d,
+            e: self.a * other.e + self.c * other.f + self.e,
+            f: self.b * other.e + self.d * other.f + self.f,
+        }
+    }
+
+    #[inline(always)]
+    pub fn apply(self, x: f64, y: f64) -> (f64, f64) {
+        (self.a * x + self.c * y + self.e, self.b * x + self.d * y + self.f)
+    }
+
+    pub fn apply_all(self, points: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        points.iter().map(|&(x, y)| self.apply(x, y)).collect()
+    }
+
+    // 逆変換（大きさ0に潰れる変換ならNone）
+    pub fn inverse(self) -> Option<Self> {
+        let det = self.a * self.d - self.b * self.c;
+        if det.abs() < 1e-12 || !det.is_finite() {
+            return None;
+        }
+        Some(Transform {
+            a: self.d / det,
+            b: -self.b / det,
+            c: -self.c / det,
+            d: self.a / det,
+            e: (self.c * self.f - self.d * self.e) / det,
+            f: (self.b * self.e - self.a * self.f) / det,
+        })
+    }
+
+    // 平行移動だけの変換ならその移動量
+    pub fn offset(self) -> Option<(f64, f64)> {
+        if self.a == 1.0 && self.b == 0.0 && self.c == 0.0 && self.d == 1.0 {
+            Some((self.e, self.f))
+        } else {
+            None
+        }
+    }
+
+    // 線の太さなどに掛ける拡大率（面積の拡大率の平方根）
+    pub fn scale_factor(self) -> f64 {
+        (self.a * self.d - self.b * self.c).abs().sqrt()
+    }
+
+    // 回転と縦横同じ拡大率だけの変換（円が円のままになる）
+    pub fn is_uniform(self) -> bool {
+        const EPS: f64 = 1e-9;
+        ((self.a - self.d).abs() < EPS && (self.b + self.c).abs() < EPS)
+            || ((self.a + self.d).abs() < EPS && (self.b - self.c).abs() < EPS)
+    }
+}
+
+// 中心 (cx, cy)、半径 rx, ry の楕円の start〜end の角度の点（ラジアン、0が右で時計回り、両端を含む）
+fn arc_points(cx: f64, cy: f64, rx: f64, ry: f64, start: f64, end: f64, segments: usize) -> Vec<(f64, f64)> {
+    (0..=segments)
+        .map(|i| {
+            let angle = start + (end - start) * i as f64 / segments as f64;
+            (cx + rx * angle.cos(), cy + ry * angle.sin())
+        })
+        .collect()
+}
+
+// 角 (x1, y1), (x2, y2)（x1 <= x2, y1 <= y2）、角の半径radiusの矩形の輪郭の点
+fn round_rect_points(x1: f64, y1: f64, x2: f64, y2: f64, radius: f64, segments: usize) -> Vec<(f64, f64)> {
+    use std::f64::consts::{FRAC_PI_2, PI};
+    if radius <= 0.0 {
+        return vec![(x1, y1), (x2, y1), (x2, y2), (x1, y2)];
+    }
+    let (lx, rx, ty, by) = (x1 + radius, x2 - radius, y1 + radius, y2 - radius);
+    let mut points = Vec::new();
+    points.extend(arc_points(rx, ty, radius, radius, -FRAC_PI_2, 0.0, segments));
+    points.extend(arc_points(rx, by, radius, radius, 0.0, FRAC_PI_2, segments));
+    points.extend(arc_points(lx, by, radius, radius, FRAC_PI_2, PI, segments));
+    points.extend(arc_points(lx, ty, radius, radius, PI, PI + FRAC_PI_2, segments));
+    points
+}
+
+// 線分 a-b を矩形 (x0, y0, x1, y1) の中に切り詰める（Liang-Barsky）。矩形と交わらなければNone
+fn clip_segment((ax, ay): (f64, f64), (bx, by): (f64, f64), (x0, y0, x1, y1): (f64, f64, f64, f64)) -> Option<((f64, f64), (f64, f64))> {
+    let (dx, dy) = (bx - ax, by - ay);
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for (p, q) in [(-dx, ax - x0), (dx, x1 - ax), (-dy, ay - y0), (dy, y1 - ay)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let r = q / p;
+        if p < 0.0 {
+            t0 = t0.max(r);
+        } else {
+            t1 = t1.min(r);
+        }
+        if t0 > t1 {
+            return None;
+        }
+    }
+    Some(((ax + dx * t0, ay + dy * t0), (ax + dx * t1, ay + dy * t1)))
+}
+
+// Luaから受け取った座標が有限の数か（infやnanで描画が終わらなくならないように）
+fn check_finite(values: &[f64]) -> mlua::Result<()> {
+    if values.iter().all(|v| v.is_finite()) {
+        Ok(())
+    } else {
+        Err(mlua::Error::external("coordinates must be finite numbers"))
+    }
+}
+
+// 中心から半径 rx, ry の楕円の dy 行目の半分の幅（その行にピクセルがなければ -1）
+// ピクセルの中心が半径 rx+0.5, ry+0.5 の楕円の内側にあれば塗る（rx == ry のときは中点アルゴリズムの円と同じピクセル）
+fn ellipse_half_width(rx: i64, ry: i64, dy: i64) -> i64 {
+    if rx < 0 || ry < 0 || dy.abs() > ry {
+        return -1;
+    }
+    // 4x²(2ry+1)² < (2rx+1)²((2ry+1)² - 4dy²) を整数で比べる（半径はi32の範囲なのでu128に収まる）
+    let a = (2 * rx + 1) as u128;
+    let b = (2 * ry + 1) as u128;
+    let dy = dy.unsigned_abs() as u128;
+    let limit = a * a * (b * b - 4 * dy * dy);
+    let inside = |x: i64| {
+        let lhs = 2 * x as u128 * b;
+        lhs * lhs < limit
+    };
+    // 浮動小数点の近似値から前後を調べて合わせる
+    let mut x = (((limit as f64).sqrt() / (2.0 * b as f64)) as i64).min(rx);
+    while x >= 0 && !inside(x) {
+        x -= 1;
+    }
+    while x < rx && inside(x + 1) {
+        x += 1;
+    }
+    x
+}
+
+// 楕円の枠の dy 行目の右半分のピクセルの範囲 (inner, outer)（中心からの距離、外側の行より広い部分が枠になる）
+fn ellipse_outline_span(rx: i64, ry: i64, dy: i64) -> (i64, i64) {
+    let outer = ellipse_half_width(rx, ry, dy);
+    let next = ellipse_half_width(rx, ry, dy.abs() + 1);
+    (outer.min(next + 1), outer)
+}
+
+// 中心からの方向 (dx, dy) が start〜end の角度に入っているか（ラジアン、0が右で時計回り）
+fn angle_in_range(dx: i64, dy: i64, start: f64, end: f64) -> bool {
+    use std::f64::consts::TAU;
+    let (start, sweep) = if end >= start { (start, end - start) } else { (end, start - end) };
+    if sweep >= TAU || (dx == 0 && dy == 0) {
+        return true;
+    }
+    let angle = (dy as f64).atan2(dx as f64);
+    (angle - start).rem_euclid(TAU) <= sweep
+}
+
+pub trait Canvas {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+    fn pixels(&self) -> &[u8];
+    fn pixels_mut(&mut self) -> &mut [u8];
+    fn state(&self) -> &CanvasState;
+    fn state_mut(&mut self) -> &mut CanvasState;
+    // 描画した範囲の通知（x1/y1は含まない）。LuaWindowはテクスチャの部分転送に使う
+    fn mark_dirty(&mut self, _x0: i32, _y0: i32, _x1: i32, _y1: i32) {}
+    fn mark_all_dirty(&mut self) {
+        let (w, h) = (self.width() as i32, self.height() as i32);
+        self.mark_dirty(0, 0, w, h);
+    }
+
+    #[inline(always)]
+    fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && x < self.width() as i32 && y < self.height() as i32
+    }
+
+    // 描画できる範囲 (x0, y0, x1, y1)（x1/y1は含まない）。クリップ矩形とキャンバスの共通部分
+    #[inline(always)]
+    fn clip_bounds(&self) -> ClipRect {
+        let (w, h) = (self.width() as i32, self.height() as i32);
+        match self.state().clip {
+            Some((x0, y0, x1, y1)) => (x0.max(0), y0.max(0), x1.min(w), y1.min(h)),
+            None => (0, 0, w, h),
+        }
+    }
+
+    #[inline(always)]
+    fn in_clip(&self, x: i32, y: i32) -> bool {
+        let (x0, y0, x1, y1) = self.clip_bounds();
+        x >= x0 && y >= y0 && x < x1 && y < y1
+    }
+
+    // 線の太さ（変換の拡大率を掛けたもの）
+    fn device_line_width(&self) -> f64 {
+        self.state().line_width * self.state().transform.scale_factor()
+    }
+
+    // 変換後の半径radius、角度sweepの円弧を折れ線にするときの分割数
+    fn arc_segments(&self, radius: f64, sweep: f64) -> usize {
+        let length = radius.abs() * self.state().transform.scale_factor() * sweep.abs();
+        ((length / 2.0).ceil() as usize).clamp(8, 1024)
+    }
+
+    // 境界チェックなし、高速化、合成方法 (setblend) に従ってブレンド（変更範囲の記録は呼び出し側で行う）
+    #[inline(always)]
+    fn unsafe_point(&mut self, x: i32, y: i32, (r, g, b, a): Rgba) {
+        let idx = (y as usize * self.width() + x as usize) * 4;
+        self.blend_index(idx, r, g, b, a as i32);
+    }
+
+    // バッファのidx（バイト位置）のピクセルに合成する
+    #[inline(always)]
+    fn blend_index(&mut self, idx: usize, r: u8, g: u8, b: u8, src_a: i32) {
+        let mode = self.state().blend;
+        blend_with(mode, &mut self.pixels_mut()[idx..idx + 4], r, g, b, src_a);
+    }
+
+    fn point(&mut self, x: i32, y: i32, color: Rgba) {
+        if !self.in_clip(x, y) {
+            return;
+        }
+        self.mark_dirty(x, y, x + 1, y + 1);
+        self.unsafe_point(x, y, color);
+    }
+
+    // クリップ範囲内なら点を描く（変更範囲は図形全体で呼び出し側がまとめて記録する）
+    #[inline(always)]
+    fn plot(&mut self, x: i32, y: i32, color: Rgba) {
+        if self.in_clip(x, y) {
+            self.unsafe_point(x, y, color);
+        }
+    }
+
+    // 範囲外は(0,0,0,0)
+    fn get_point(&self, x: i32, y: i32) -> Rgba {
+        if !self.in_bounds(x, y) {
+            return (0, 0, 0, 0);
+        }
+        let idx = (y as usize * self.width() + x as usize) * 4;
+        let px = &self.pixels()[idx..idx + 4];
+        (px[0], px[1], px[2], px[3])
+    }
+
+    fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Rgba) {
+        self.mark_dirty(x0.min(x1), y0.min(y1), x0.max(x1) + 1, y0.max(y1) + 1);
+        let mut x0 = x0;
+        let mut y0 = y0;
+        let dx = (x1 - x0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let dy = -(y1 - y0).abs();
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        loop {
+            self.plot(x0, y0, color);
+            if x0 == x1 && y0 == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+
+    // 中点アルゴリズムの円と同じピクセルを行ごとに描く
+    fn circle(&mut self, cx: i32, cy: i32, radius: i32, color: Rgba) {
+        self.ellipse(cx, cy, radius, radius, color);
+    }
+
+    // 被覆率 (0.0〜1.0) を掛けてアルファブレンド（範囲チェック・変更範囲の記録は呼び出し側で行う）
+    #[inline(always)]
+    fn blend_coverage(&mut self, x: i32, y: i32, (r, g, b, a): Rgba, coverage: f64) {
+        let idx = (y as usize * self.width() + x as usize) * 4;
+        let src_a = (a as f64 * coverage).round() as i32;
+        if src_a > 0 {
+            self.blend_index(idx, r, g, b, src_a);
+        }
+    }
+
+    // 線の太さ・アンチエイリアスの設定に従って線を描く（整数座標がピクセルの中心）
+    fn stroke_line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Rgba) {
+        if ![x0, y0, x1, y1].iter().all(|v| v.is_finite()) {
+            return;
+        }
+        let (line_width, antialias) = (self.device_line_width(), self.state().antialias);
+        let half = (line_width / 2.0).max(0.5);
+        let reach = if antialias { half + 0.5 } else { half };
+        // 線が届かない部分を切り捨てる（座標が大きくてもクリップ範囲の大きさに比例した時間で描く）
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+        let margin = reach + 1.0;
+        let bounds = (clip_x0 as f64 - margin, clip_y0 as f64 - margin, clip_x1 as f64 + margin, clip_y1 as f64 + margin);
+        let ((x0, y0), (x1, y1)) = match clip_segment((x0, y0), (x1, y1), bounds) {
+            Some(segment) => segment,
+            None => return,
+        };
+        if line_width <= 1.0 && !antialias {
+            self.line(x0.round() as i32, y0.round() as i32, x1.round() as i32, y1.round() as i32, color);
+            return;
+        }
+        // 線分からの距離で塗る（端は丸くなる）
+        // アンチエイリアスなしは被覆率50%以上のピクセルを塗るのと同じ
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let len2 = dx * dx + dy * dy;
+        let y_min = ((y0.min(y1) - reach).floor() as i32).max(clip_y0);
+        let y_max = ((y0.max(y1) + reach).ceil() as i32).min(clip_y1 - 1);
+        self.mark_dirty(
+            ((x0.min(x1) - reach).floor() as i32).max(clip_x0),
+            y_min,
+            ((x0.max(x1) + reach).ceil() as i32).min(clip_x1 - 1) + 1,
+            y_max + 1,
+        );
+        for y in y_min..=y_max {
+            let yc = y as f64;
+            // この行に届く線分の範囲から、調べるxの範囲を絞る
+            let (t0, t1) = if dy.abs() < 1e-9 {
+                (0.0, 1.0)
+            } else {
+                let ta = (yc - reach - y0) / dy;
+                let tb = (yc + reach - y0) / dy;
+                (ta.min(tb).clamp(0.0, 1.0), ta.max(tb).clamp(0.0, 1.0))
+            };
+            let (xa, xb) = (x0 + dx * t0, x0 + dx * t1);
+            let x_min = ((xa.min(xb) - reach).floor() as i32).max(clip_x0);
+            let x_max = ((xa.max(xb) + reach).ceil() as i32).min(clip_x1 - 1);
+            for x in x_min..=x_max {
+                let xc = x as f64;
+                let t = if len2 > 0.0 { (((xc - x0) * dx + (yc - y0) * dy) / len2).clamp(0.0, 1.0) } else { 0.0 };
+                let d = (xc - (x0 + dx * t)).hypot(yc - (y0 + dy * t));
+                let coverage = if antialias { (half + 0.5 - d).clamp(0.0, 1.0) } else if d <= half { 1.0 } else { 0.0 };
+                if coverage > 0.0 {
+                    self.blend_coverage(x, y, color, coverage);
+                }
+            }
+        }
+    }
+
+    // 線の太さ・アンチエイリアスの設定に従って円を描く
+    fn stroke_circle(&mut self, cx: f64, cy: f64, radius: f64, color: Rgba) {
+        let (line_width, antialias) = (self.device_line_width(), self.state().antialias);
+        if line_width <= 1.0 && !antialias {
+            self.circle(cx.round() as i32, cy.round() as i32, radius.round() as i32, color);
+            return;
+        }
+        let half = (line_width / 2.0).max(0.5);
+        let reach = if antialias { half + 0.5 } else { half };
+        self.fill_rows(cx, cy, radius - reach, radius + reach, color, &|d| {
+            let d = (d - radius).abs();
+            if antialias { (half + 0.5 - d).clamp(0.0, 1.0) } else if d <= half { 1.0 } else { 0.0 }
+        });
+    }
+
+    // 塗りつぶした円（アンチエイリアスの設定に従う）
+    fn fill_disc(&mut self, cx: f64, cy: f64, radius: f64, color: Rgba) {
+        if !self.state().antialias {
+            self.fill_circle(cx.round() as i32, cy.round() as i32, radius.round() as i32, color);
+            return;
+        }
+        self.fill_rows(cx, cy, 0.0, radius + 0.5, color, &|d| (radius + 0.5 - d).clamp(0.0, 1.0));
+    }
+
+    // 中心からの距離で被覆率を決めて、半径inner〜outerの範囲を塗る（inner未満のピクセルは調べない）
+    fn fill_rows(&mut self, cx: f64, cy: f64, inner: f64, outer: f64, color: Rgba, coverage: &dyn Fn(f64) -> f64) {
+        if outer <= 0.0 {
+            return;
+        }
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+        let y_min = ((cy - outer).floor() as i32).max(clip_y0);
+        let y_max = ((cy + outer).ceil() as i32).min(clip_y1 - 1);
+        self.mark_dirty(((cx - outer).floor() as i32).max(clip_x0), y_min, ((cx + outer).ceil() as i32).min(clip_x1 - 1) + 1, y_max + 1);
+        for y in y_min..=y_max {
+            let dy = y as f64 - cy;
+            if dy.abs() > outer {
+                continue;
+            }
+            let span = (outer * outer - dy * dy).sqrt();
+            let hole = if inner > 0.0 && dy.abs() < inner { (inner * inner - dy * dy).sqrt() } else { -1.0 };
+            let x_min = ((cx - span).floor() as i32).max(clip_x0);
+            let x_max = ((cx + span).ceil() as i32).min(clip_x1 - 1);
+            for x in x_min..=x_max {
+                let dx = x as f64 - cx;
+                if dx.abs() < hole {
+                    continue;
+                }
+                let c = coverage(dx.hypot(dy));
+                if c > 0.0 {
+                    self.blend_coverage(x, y, color, c);
+                }
+            }
+        }
+    }
+
+    // 角 (x1, y1), (x2, y2) を結ぶ矩形の枠（両端を含む）
+    fn rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, color: Rgba) {
+        let (x1, y1, x2, y2) = (x1 as f64, y1 as f64, x2 as f64, y2 as f64);
+        self.stroke_line(x1, y1, x2, y1, color);
+        self.stroke_line(x2, y1, x2, y2, color);
+        self.stroke_line(x2, y2, x1, y2, color);
+        self.stroke_line(x1, y2, x1, y1, color);
+    }
+
+    // 角 (x1, y1), (x2, y2) の矩形を塗りつぶす（両端を含む、キャンバス外はクリップ）
+    fn fill_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, color: Rgba) {
+        let (_, clip_y0, _, clip_y1) = self.clip_bounds();
+        for y in y1.min(y2).max(clip_y0)..=y1.max(y2).min(clip_y1 - 1) {
+            self.fill_span(y as i64, x1 as i64, x2 as i64, color);
+        }
+    }
+
+    // y行の x0〜x1 を塗る（両端を含む、クリップ範囲外は塗らない）
+    fn fill_span(&mut self, y: i64, x0: i64, x1: i64, (r, g, b, a): Rgba) {
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+        if y < clip_y0 as i64 || y >= clip_y1 as i64 {
+            return;
+        }
+        let x_min = x0.min(x1).max(clip_x0 as i64);
+        let x_max = x0.max(x1).min(clip_x1 as i64 - 1);
+        if x_min > x_max {
+            return;
+        }
+        // クリップ後はキャンバス内なのでi32に収まる
+        let (y, x_min, x_max) = (y as i32, x_min as i32, x_max as i32);
+        self.mark_dirty(x_min, y, x_max + 1, y + 1);
+        let start = (y as usize * self.width() + x_min as usize) * 4;
+        let end = (y as usize * self.width() + x_max as usize + 1) * 4;
+        let mode = self.state().blend;
+        for px in self.pixels_mut()[start..end].chunks_mut(4) {
+            blend_with(mode, px, r, g, b, a as i32);
+        }
+    }
+
+    // 中心の行 cy から上下 radius 行のうち、クリップ範囲に入る行（中心からの相対位置）
+    fn clipped_rows(&self, cy: i64, radius: i64) -> std::ops::RangeInclusive<i64> {
+        let (_, clip_y0, _, clip_y1) = self.clip_bounds();
+        ((cy - radius).max(clip_y0 as i64) - cy)..=((cy + radius).min(clip_y1 as i64 - 1) - cy)
+    }
+
+    // 左右対称な枠の1行（left, right から外側へ inner〜outer の範囲、inner が0なら1本につなげる）
+    fn outline_row(&mut self, y: i64, left: i64, right: i64, (inner, outer): (i64, i64), color: Rgba) {
+        if inner == 0 {
+            self.fill_span(y, left - outer, right + outer, color);
+        } else {
+            self.fill_span(y, left - outer, left - inner, color);
+            self.fill_span(y, right + inner, right + outer, color);
+        }
+    }
+
+    // y行の x0〜x1 のうち、中心 (cx, cy) から見た方向が start〜end の角度に入る部分を塗る
+    fn fill_span_in_angle(&mut self, y: i64, x0: i64, x1: i64, (cx, cy): (i64, i64), (start, end): (f64, f64), color: Rgba) {
+        let (clip_x0, _, clip_x1, _) = self.clip_bounds();
+        let (x0, x1) = (x0.min(x1).max(clip_x0 as i64), x0.max(x1).min(clip_x1 as i64 - 1));
+        if x0 > x1 {
+            return;
+        }
+        // 行の上で角度の判定が変わるのは、中心の真上・真下と、開始・終了の角度の半直線が行と交わるところだけ
+        let dy = y - cy;
+        let mut breaks = vec![x0, x1 + 1, cx, cx + 1];
+        for angle in [start, end] {
+            let (sin, cos) = angle.sin_cos();
+            if sin * dy as f64 > 0.0 {
+                let cross = (cx as f64 + dy as f64 * cos / sin).clamp(x0 as f64 - 2.0, x1 as f64 + 2.0).floor() as i64;
+                breaks.extend(cross - 1..=cross + 2);
+            }
+        }
+        breaks.retain(|&x| x >= x0 && x <= x1 + 1);
+        breaks.sort_unstable();
+        breaks.dedup();
+        for pair in breaks.windows(2) {
+            if angle_in_range(pair[0] - cx, dy, start, end) {
+                self.fill_span(y, pair[0], pair[1] - 1, color);
+            }
+        }
+    }
+
+    // 塗りつぶした円（circleの枠と同じピクセルまで塗る）
+    fn fill_circle(&mut self, cx: i32, cy: i32, radius: i32, color: Rgba) {
+        self.fill_ellipse(cx, cy, radius, radius, color);
+    }
+
+    // 中心 (cx, cy)、半径 rx, ry の楕円の枠（クリップ範囲の行だけ調べる）
+    fn ellipse(&mut self, cx: i32, cy: i32, rx: i32, ry: i32, color: Rgba) {
+        if rx < 0 || ry < 0 {
+            return;
+        }
+        let (cx, cy, rx, ry) = (cx as i64, cy as i64, rx as i64, ry as i64);
+        for dy in self.clipped_rows(cy, ry) {
+            self.outline_row(cy + dy, cx, cx, ellipse_outline_span(rx, ry, dy), color);
+        }
+    }
+
+    // 塗りつぶした楕円（枠と同じピクセルまで塗る）
+    fn fill_ellipse(&mut self, cx: i32, cy: i32, rx: i32, ry: i32, color: Rgba) {
+        if rx < 0 || ry < 0 {
+            return;
+        }
+        let (cx, cy, rx, ry) = (cx as i64, cy as i64, rx as i64, ry as i64);
+        for dy in self.clipped_rows(cy, ry) {
+            let half = ellipse_half_width(rx, ry, dy);
+            self.fill_span(cy + dy, cx - half, cx + half, color);
+        }
+    }
+
+    // 頂点を順に結んだ多角形の枠（最後の点と最初の点も結ぶ）
+    fn polygon(&mut self, points: &[(f64, f64)], color: Rgba) {
+        let n = points.len();
+        for i in 0..n {
+            let (x0, y0) = points[i];
+            let (x1, y1) = points[(i + 1) % n];
+            self.stroke_line(x0, y0, x1, y1, color);
+        }
+    }
+
+    // 多角形を塗りつぶす（偶奇規則、ピクセルの中心=整数座標が内側なら塗る）
+    // 左・上の辺上は塗り、右・下の辺上は塗らないので、辺を共有する多角形を並べても隙間も重なりもできない
+    // 回転・拡大した矩形や円も面積どおりの大きさになる
+    fn fill_polygon(&mut self, points: &[(f64, f64)], color: Rgba) {
+        if points.len() < 3 {
+            return;
+        }
+        let (_, clip_y0, _, clip_y1) = self.clip_bounds();
+        let min_y = points.iter().map(|p| p.1).fold(f64::INFINITY, f64::min).ceil().max(clip_y0 as f64) as i32;
+        let max_y = (points.iter().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max).ceil() - 1.0).min(clip_y1 as f64 - 1.0) as i32;
+        let n = points.len();
+        let mut xs: Vec<f64> = Vec::new();
+        for y in min_y..=max_y {
+            let yc = y as f64;
+            xs.clear();
+            for i in 0..n {
+                let (x0, y0) = points[i];
+                let (x1, y1) = points[(i + 1) % n];
+                let (top, bottom) = if y0 < y1 { ((x0, y0), (x1, y1)) } else { ((x1, y1), (x0, y0)) };
+                if yc >= top.1 && yc < bottom.1 {
+                    xs.push(top.0 + (yc - top.1) * (bottom.0 - top.0) / (bottom.1 - top.1));
+                }
+            }
+            xs.sort_by(|a, b| a.total_cmp(b));
+            // 中心が xa <= x < xb のピクセル
+            for pair in xs.chunks_exact(2) {
+                let (a, b) = (pair[0].ceil() as i64, (pair[1].ceil() - 1.0) as i64);
+                if a <= b {
+                    self.fill_span(y as i64, a, b, color);
+                }
+            }
+        }
+    }
+
+    // 円弧（角度はラジアン、0が右で時計回り、circleの枠のうち角度の範囲に入るピクセル）
+    fn arc(&mut self, cx: i32, cy: i32, radius: i32, start: f64, end: f64, color: Rgba) {
+        if radius < 0 {
+            return;
+        }
+        let (cx, cy, radius) = (cx as i64, cy as i64, radius as i64);
+        for dy in self.clipped_rows(cy, radius) {
+            let (inner, outer) = ellipse_outline_span(radius, radius, dy);
+            let y = cy + dy;
+            if inner == 0 {
+                self.fill_span_in_angle(y, cx - outer, cx + outer, (cx, cy), (start, end), color);
+            } else {
+                self.fill_span_in_angle(y, cx - outer, cx - inner, (cx, cy), (start, end), color);
+                self.fill_span_in_angle(y, cx + inner, cx + outer, (cx, cy), (start, end), color);
+            }
+        }
+    }
+
+    // 扇形を塗りつぶす（角度はarcと同じ）
+    fn pie(&mut self, cx: i32, cy: i32, radius: i32, start: f64, end: f64, color: Rgba) {
+        if radius < 0 {
+            return;
+        }
+        let (cx, cy, radius) = (cx as i64, cy as i64, radius as i64);
+        for dy in self.clipped_rows(cy, radius) {
+            let half = ellipse_half_width(radius, radius, dy);
+            self.fill_span_in_angle(cy + dy, cx - half, cx + half, (cx, cy), (start, end), color);
+        }
+    }
+
+    // 角を丸めた矩形の枠（角 (x1, y1), (x2, y2) は両端を含む）
+    fn round_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, radius: i32, color: Rgba) {
+        let (left, right) = (x1.min(x2) as i64, x1.max(x2) as i64);
+        let (top, bottom) = (y1.min(y2) as i64, y1.max(y2) as i64);
+        let radius = (radius as i64).min((right - left) / 2).min((bottom - top) / 2);
+        if radius <= 0 {
+            self.rect(x1, y1, x2, y2, color);
+            return;
+        }
+        // 角の円の中心
+        let (lx, rx, ty, by) = (left + radius, right - radius, top + radius, bottom - radius);
+        let (_, clip_y0, _, clip_y1) = self.clip_bounds();
+        for y in top.max(clip_y0 as i64)..=bottom.min(clip_y1 as i64 - 1) {
+            // 角の円の中心の行からの距離（直線部分は0）
+            let dy = (ty - y).max(y - by).max(0);
+            self.outline_row(y, lx, rx, ellipse_outline_span(radius, radius, dy), color);
+        }
+    }
+
+    // 角を丸めた矩形を塗りつぶす
+    fn fill_round_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, radius: i32, color: Rgba) {
+        let (left, right) = (x1.min(x2) as i64, x1.max(x2) as i64);
+        let (top, bottom) = (y1.min(y2) as i64, y1.max(y2) as i64);
+        let radius = (radius as i64).min((right - left) / 2).min((bottom - top) / 2).max(0);
+        let (lx, rx, ty, by) = (left + radius, right - radius, top + radius, bottom - radius);
+        let (_, clip_y0, _, clip_y1) = self.clip_bounds();
+        for y in top.max(clip_y0 as i64)..=bottom.min(clip_y1 as i64 - 1) {
+            // 角の部分は円の幅だけ内側から塗る
+            let half = ellipse_half_width(radius, radius, (ty - y).max(y - by).max(0));
+            self.fill_span(y, lx - half, rx + half, color);
+        }
+    }
+
+    // ブレンドせずに全体（クリップ中はクリップ範囲）を塗りつぶす
+    fn clear(&mut self, (r, g, b, a): Rgba) {
+        let (x0, y0, x1, y1) = self.clip_bounds();
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        let width = self.width();
+        for y in y0 as usize..y1 as usize {
+            let start = (y * width + x0 as usize) * 4;
+            let end = (y * width + x1 as usize) * 4;
+            for px in self.pixels_mut()[start..end].chunks_mut(4) {
+                px.copy_from_slice(&[r, g, b, a]);
+            }
+        }
+        self.mark_dirty(x0, y0, x1, y1);
+    }
+
+    // 内容（クリップ中はクリップ範囲の内容）を (dx, dy) ずらし、空いた部分を指定色で埋める
+    fn scroll(&mut self, dx: i32, dy: i32, (r, g, b, a): Rgba) {
+        let (x0, y0, x1, y1) = self.clip_bounds();
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        let width = self.width();
+        let old = self.pixels().to_vec();
+        let buffer = self.pixels_mut();
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let nx = x - dx;
+                let ny = y - dy;
+                let idx = (y as usize * width + x as usize) * 4;
+                if nx >= x0 && nx < x1 && ny >= y0 && ny < y1 {
+                    let src = (ny as usize * width + nx as usize) * 4;
+                    buffer[idx..idx + 4].copy_from_slice(&old[src..src + 4]);
+                } else {
+                    buffer[idx..idx + 4].copy_from_slice(&[r, g, b, a]);
+                }
+            }
+        }
+        self.mark_dirty(x0, y0, x1, y1);
+    }
+
+    // 主フォントと代替フォント（読み込めなければLuaのエラー）
+    fn fonts(&self) -> mlua::Result<Vec<std::sync::Arc<fontdue::Font>>> {
+        let state = self.state();
+        let mut specs = vec![(state.fontpath.clone(), state.fontindex)];
+        specs.extend(state.fallback_fonts.iter().cloned());
+        get_fonts(&specs).map_err(mlua::Error::external)
+    }
+
+    // テキストを現在のフォント・サイズで1行に並べる
+    fn text_layout(&self, text: &str) -> mlua::Result<crate::luafont::TextLayout> {
+        Ok(layout_line(&self.fonts()?, self.state().text_font_size as f32, text))
+    }
+
+    // (x, y) を左上としてテキストを描画し、幅・高さを返す（変換を適用する）
+    fn text(&mut self, x: i32, y: i32, text: &str) -> mlua::Result<(usize, usize)> {
+        let layout = self.text_layout(text)?;
+        self.draw_glyphs(x, y, &layout.glyphs);
+        Ok((layout.width, layout.height))
+    }
+
+    // (x, y) を左上とする幅width・高さheightの枠に、改行・折り返し・寄せを行ってテキストを描く
+    // width/height が None なら折り返さない・行数を制限しない。幅・高さ・行数・省略したかを返す
+    fn text_box(&mut self, x: i32, y: i32, width: Option<i32>, height: Option<i32>, text: &str, opts: &TextBoxOptions) -> mlua::Result<(usize, usize, usize, bool)> {
+        let fonts = self.fonts()?;
+        let size = self.state().text_font_size as f32;
+        let (ascent, descent, _) = line_metrics(&fonts[0], size);
+        let line_height = ascent - descent;
+        let advance = line_height * opts.linespacing;
+        let max_width = width.map(|w| w.max(0) as f32);
+        let mut lines = wrap_text(&fonts, size, text, max_width, opts.wrap);
+        // 高さに入る行だけ残す（少なくとも1行、行送りが0以下なら1行だけ）
+        let mut truncated = false;
+        if let Some(h) = height {
+            let max_lines = if advance > 0.0 {
+                (((h as f32 - line_height) / advance).floor() as i64).saturating_add(1).max(1) as usize
+            } else {
+                1
+            };
+            if lines.len() > max_lines {
+                lines.truncate(max_lines);
+                truncated = true;
+            }
+        }
+        if let (true, Some(max_width)) = (opts.ellipsis, max_width) {
+            let last = lines.len().saturating_sub(1);
+            for (i, line) in lines.iter_mut().enumerate() {
+                *line = ellipsize(&fonts, size, line, max_width, truncated && i == last);
+            }
+        }
+        let layouts: Vec<_> = lines.iter().map(|line| layout_line(&fonts, size, line)).collect();
+        let content_width = layouts.iter().map(|l| l.width).max().unwrap_or(0);
+        let content_height = match layouts.len() {
+            0 => 0.0,
+            1 => line_height,
+            n => line_height + advance * (n - 1) as f32,
+        };
+        let box_width = width.map_or(content_width as f32, |w| w as f32);
+        let offset_y = height.map_or(0.0, |h| (h as f32 - content_height) * opts.valign);
+        for (i, layout) in layouts.iter().enumerate() {
+            let offset_x = (box_width - layout.width as f32) * opts.align;
+            let line_y = offset_y + advance * i as f32;
+            self.draw_glyphs(x.saturating_add(offset_x.round() as i32), y.saturating_add(line_y.round() as i32), &layout.glyphs);
+        }
+        Ok((content_width, content_height.ceil() as usize, layouts.len(), truncated))
+    }
+
+    // 並べたグリフを (x, y) からの位置に文字色で描く
+    fn draw_glyphs(&mut self, x: i32, y: i32, glyphs: &[PlacedGlyph]) {
+        let (r, g, b, a) = self.state().text_color;
+        let mode = self.state().blend;
+        if let Some((dx, dy)) = self.int_offset(&[(x, y)]) {
+            // 平行移動だけなら逆変換せずにビットマップをそのまま合成する
+            let (x, y) = ((x + dx) as i64, (y + dy) as i64);
+            let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+            let stride = self.width();
+            for placed in glyphs {
+                let metrics = &placed.glyph.metrics;
+                // 文字の位置をi64でクリップする（キャンバスの端の座標でも溢れない）
+                let (gx, gy) = (x + placed.x as i64, y + placed.y as i64);
+                let (x0, x1) = (gx.max(clip_x0 as i64), (gx + metrics.width as i64).min(clip_x1 as i64));
+                let (y0, y1) = (gy.max(clip_y0 as i64), (gy + metrics.height as i64).min(clip_y1 as i64));
+                if x0 >= x1 || y0 >= y1 {
+                    continue;
+                }
+                self.mark_dirty(x0 as i32, y0 as i32, x1 as i32, y1 as i32);
+                for py in y0..y1 {
+                    let iy = (py - gy) as usize;
+                    let row = &placed.glyph.bitmap[iy * metrics.width..(iy + 1) * metrics.width];
+                    for px in x0..x1 {
+                        let cov = row[(px - gx) as usize];
+                        if cov > 0 {
+                            let idx = (py as usize * stride + px as usize) * 4;
+                            blend_with(mode, &mut self.pixels_mut()[idx..idx + 4], r, g, b, cov as i32 * a as i32 / 255);
+                        }
+                    }
+                }
+            }
+            return;
+        }
+        for placed in glyphs {
+            let (metrics, bitmap) = (&placed.glyph.metrics, &placed.glyph.bitmap);
+            let matrix = Transform::translate(x as f64 + placed.x as f64 - 0.5, y as f64 + placed.y as f64 - 0.5);
+            self.draw_transformed(metrics.width, metrics.height, matrix, mode, &|u, v| {
+                let cov = bitmap[v as usize * metrics.width + u as usize];
+                (cov > 0).then_some((r, g, b, ((cov as i32 * a as i32) / 255) as u8))
+            });
+        }
+    }
+
+    // (x, y) から境界色(boundary)か塗りつぶし色で囲まれた範囲を塗り、塗ったピクセル数を返す
+    // クリップ範囲の外は境界として扱う
+    fn paint(&mut self, x: i32, y: i32, fill: Rgba, boundary: Rgba) -> usize {
+        if !self.in_clip(x, y) {
+            return 0;
+        }
+        let (w, h) = (self.width() as i32, self.height() as i32);
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+        // 既に塗りつぶし色か境界色なら何もしない
+        let pixel0 = self.get_point(x, y);
+        if pixel0 == fill || pixel0 == boundary {
+            return 0;
+        }
+        let mut stack = std::mem::take(&mut self.state_mut().fillpaint_stack);
+        let mut visited = std::mem::take(&mut self.state_mut().fillpaint_visited);
+        stack.clear();
+        visited.clear();
+        visited.resize((w * h) as usize, false);
+        let mut count: usize = 0;
+        let (mut x_min, mut y_min, mut x_max, mut y_max) = (x, y, x, y);
+        stack.push((x, y));
+        while let Some((cx, cy)) = stack.pop() {
+            if cx < clip_x0 || cy < clip_y0 || cx >= clip_x1 || cy >= clip_y1 {
+                continue;
+            }
+            let idx = cy as usize * w as usize + cx as usize;
+            if visited[idx] {
+                continue;
+            }
+            let pixel = self.get_point(cx, cy);
+            if pixel == boundary || pixel == fill {
+                continue;
+            }
+            self.unsafe_point(cx, cy, fill);
+            (x_min, y_min, x_max, y_max) = (x_min.min(cx), y_min.min(cy), x_max.max(cx), y_max.max(cy));
+            visited[idx] = true;
+            stack.push((cx + 1, cy));
+            stack.push((cx - 1, cy));
+            stack.push((cx, cy + 1));
+            stack.push((cx, cy - 1));
+            count += 1;
+        }
+        if count > 0 {
+            self.mark_dirty(x_min, y_min, x_max + 1, y_max + 1);
+        }
+        // 作業領域は次回のために残す
+        self.state_mut().fillpaint_stack = stack;
+        self.state_mut().fillpaint_visited = visited;
+        count
+    }
+
+    // 画像の src = (sx, sy, width, height) の範囲を (x, y) に合成して描画（変換を適用する）
+    fn draw_image(&mut self, img: &image::DynamicImage, x: i32, y: i32, (sx, sy, width, height): (u32, u32, u32, u32)) {
+        // 切り取り
+        let subimg = img.crop_imm(sx, sy, width, height).to_rgba8();
+        let region = PixelRegion {
+            x: 0,
+            y: 0,
+            width: subimg.width() as usize,
+            height: subimg.height() as usize,
+            data: subimg.into_raw(),
+        };
+        self.draw_region(&region, x, y, &BlitOptions::default());
+    }
+
+    // 切り出したピクセルを (x, y) に転送する（変換を適用する）
+    fn draw_region(&mut self, region: &PixelRegion, x: i32, y: i32, opts: &BlitOptions) {
+        if let Some((dx, dy)) = self.int_offset(&[(x, y)]) {
+            self.blit_region(region, x + dx, y + dy, opts);
+            return;
+        }
+        let mode = if opts.blend { self.state().blend } else { BlendMode::Replace };
+        let matrix = Transform::translate(x as f64 - 0.5, y as f64 - 0.5);
+        self.draw_transformed(region.width, region.height, matrix, mode, &|u, v| {
+            let (r, g, b, a) = region.sample_nearest(u, v);
+            if opts.colorkey == Some((r, g, b)) {
+                return None;
+            }
+            Some((r, g, b, (a as i32 * opts.opacity as i32 / 255) as u8))
+        });
+    }
+
+    // 切り出したピクセルを回転・拡大・反転して (x, y) に描く（キャンバスの変換も適用する）
+    // region.x, region.y は転送元での位置で、opts.src の範囲の外を除いた分だけずらして置く
+    fn draw_image_ex(&mut self, region: &PixelRegion, x: f64, y: f64, opts: &DrawImageOptions) {
+        if region.width == 0 || region.height == 0 {
+            return;
+        }
+        let (src_x, src_y, src_w, src_h) = opts.src.unwrap_or((0, 0, None, None));
+        // 反転は転送元の範囲の中で行う
+        let width = src_w.unwrap_or(region.x - src_x + region.width as i32) as f64;
+        let height = src_h.unwrap_or(region.y - src_y + region.height as i32) as f64;
+        let flip = Transform {
+            a: if opts.flipx { -1.0 } else { 1.0 },
+            d: if opts.flipy { -1.0 } else { 1.0 },
+            e: if opts.flipx { width } else { 0.0 },
+            f: if opts.flipy { height } else { 0.0 },
+            ..Transform::IDENTITY
+        };
+        let matrix = Transform::translate(x - 0.5, y - 0.5)
+            .multiply(Transform::rotate(opts.angle))
+            .multiply(Transform::scale(opts.sx, opts.sy))
+            .multiply(Transform::translate(-opts.ox, -opts.oy))
+            .multiply(flip)
+            .multiply(Transform::translate((region.x - src_x) as f64, (region.y - src_y) as f64));
+        let mode = self.state().blend;
+        let alpha = opts.alpha as i32;
+        self.draw_transformed(region.width, region.height, matrix, mode, &|u, v| {
+            let (r, g, b, a) = if opts.linear { region.sample_linear(u, v) } else { region.sample_nearest(u, v) };
+            (a > 0).then_some((r, g, b, (a as i32 * alpha / 255) as u8))
+        });
+    }
+
+    // 幅width、高さheightの転送元を、matrix（転送元の座標→変換前の座標）とキャンバスの変換で置いて描く
+    // 転送元の座標はピクセルの左上が整数。転送先の各ピクセルの中心を逆変換して、
+    // 転送元の範囲内なら sample(u, v) の色を合成する（Noneなら描かない）
+    fn draw_transformed(&mut self, width: usize, height: usize, matrix: Transform, mode: BlendMode, sample: &dyn Fn(f64, f64) -> Option<Rgba>) {
+        let t = self.state().transform.multiply(matrix);
+        let inv = match t.inverse() {
+            Some(inv) => inv,
+            None => return,
+        };
+        let (w, h) = (width as f64, height as f64);
+        let corners = [t.apply(0.0, 0.0), t.apply(w, 0.0), t.apply(0.0, h), t.apply(w, h)];
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+        let x_min = (corners.iter().map(|p| p.0).fold(f64::INFINITY, f64::min).floor() as i32).max(clip_x0);
+        let x_max = (corners.iter().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max).ceil() as i32).min(clip_x1 - 1);
+        let y_min = (corners.iter().map(|p| p.1).fold(f64::INFINITY, f64::min).floor() as i32).max(clip_y0);
+        let y_max = (corners.iter().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max).ceil() as i32).min(clip_y1 - 1);
+        if x_min > x_max || y_min > y_max {
+            return;
+        }
+        self.mark_dirty(x_min, y_min, x_max + 1, y_max + 1);
+        let stride = self.width();
+        for y in y_min..=y_max {
+            for x in x_min..=x_max {
+                let (u, v) = inv.apply(x as f64, y as f64);
+                if u < 0.0 || v < 0.0 || u >= w || v >= h {
+                    continue;
+                }
+                if let Some((r, g, b, a)) = sample(u, v) {
+                    let idx = (y as usize * stride + x as usize) * 4;
+                    blend_with(mode, &mut self.pixels_mut()[idx..idx + 4], r, g, b, a as i32);
+                }
+            }
+        }
+    }
+
+    // (x, y, width, height) の範囲をキャンバス内にクリップして切り出す
+    fn copy_region(&self, x: i32, y: i32, width: i32, height: i32) -> PixelRegion {
+        let x0 = x.max(0);
+        let y0 = y.max(0);
+        let x1 = (x + width).min(self.width() as i32);
+        let y1 = (y + height).min(self.height() as i32);
+        if x0 >= x1 || y0 >= y1 {
+            return PixelRegion { x: x0, y: y0, width: 0, height: 0, data: Vec::new() };
+        }
+        let (w, h) = ((x1 - x0) as usize, (y1 - y0) as usize);
+        let mut data = Vec::with_capacity(w * h * 4);
+        for y in y0 as usize..y1 as usize {
+            let start = (y * self.width() + x0 as usize) * 4;
+            data.extend_from_slice(&self.pixels()[start..start + w * 4]);
+        }
+        PixelRegion { x: x0, y: y0, width: w, height: h, data }
+    }
+
+    // 切り出したピクセルを (x, y) に転送する（変換は適用しない）
+    fn blit_region(&mut self, region: &PixelRegion, x: i32, y: i32, opts: &BlitOptions) {
+        let width = self.width();
+        let (clip_x0, clip_y0, clip_x1, clip_y1) = self.clip_bounds();
+        // 転送先の矩形をクリップする（座標が大きくても溢れないようにi64で計算）
+        let dx0 = (x as i64).max(clip_x0 as i64);
+        let dy0 = (y as i64).max(clip_y0 as i64);
+        let dx1 = (x as i64 + region.width as i64).min(clip_x1 as i64);
+        let dy1 = (y as i64 + region.height as i64).min(clip_y1 as i64);
+        if dx0 >= dx1 || dy0 >= dy1 {
+            return;
+        }
+        // クリップ後の矩形はキャンバス内なのでi32に収まる
+        self.mark_dirty(dx0 as i32, dy0 as i32, dx1 as i32, dy1 as i32);
+        let ix0 = (dx0 - x as i64) as usize;
+        let iy0 = (dy0 - y as i64) as usize;
+        let ix1 = (dx1 - x as i64) as usize;
+        let iy1 = (dy1 - y as i64) as usize;
+        let plain = !opts.blend && opts.colorkey.is_none() && opts.opacity == 255;
+        let mode = if opts.blend { self.state().blend } else { BlendMode::Replace };
+        for iy in iy0..iy1 {
+            let src_row = &region.data[(iy * region.width) * 4..(iy * region.width + region.width) * 4];
+            let dst_start = ((dy0 as usize + (iy - iy0)) * width + dx0 as usize) * 4;
+            let dst_row = &mut self.pixels_mut()[dst_start..dst_start + (ix1 - ix0) * 4];
+            if plain {
+                dst_row.copy_from_slice(&src_row[ix0 * 4..ix1 * 4]);
+                continue;
+            }
+            for (src, dst) in src_row[ix0 * 4..ix1 * 4].chunks(4).zip(dst_row.chunks_mut(4)) {
+                if opts.colorkey == Some((src[0], src[1], src[2])) {
+                    continue;
+                }
+                let a = src[3] as i32 * opts.opacity as i32 / 255;
+                blend_with(mode, dst, src[0], src[1], src[2], a);
+            }
+        }
+    }
+
+    // (x, y, width, height) の範囲を画像として切り出す（キャンバス外は透明）
+    fn capture_image(&self, x: i32, y: i32, width: u32, height: u32) -> image::DynamicImage {
+        let mut buf = vec![0u8; (width * height * 4) as usize];
+        for iy in 0..height {
+            for ix in 0..width {
+                let sx = x + ix as i32;
+                let sy = y + iy as i32;
+                if !self.in_bounds(sx, sy) {
+                    continue;
+                }
+                let idx_src = (sy as usize * self.width() + sx as usize) * 4;
+                let idx_dst = (iy as usize * width as usize + ix as usize) * 4;
+                buf[idx_dst..idx_dst + 4].copy_from_slice(&self.pixels()[idx_src..idx_src + 4]);
+            }
+        }
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_vec(width, height, buf).unwrap())
+    }
+
+    // 省略された色の既定値（互換モードでは0）
+    fn color(&self, r: Option<u8>, g: Option<u8>, b: Option<u8>, a: Option<u8>) -> Rgba {
+        let default = if self.state().compat { 0 } else { 255 };
+        (r.unwrap_or(default), g.unwrap_or(default), b.unwrap_or(default), a.unwrap_or(255))
+    }
+
+    // rect/fillrectの引数を角の座標 (x1, y1, x2, y2) にする（互換モードでは (x, y, width, height)）
+    fn rect_corners(&self, a: i32, b: i32, c: i32, d: i32, fill: bool) -> Option<(i32, i32, i32, i32)> {
+        if !self.state().compat {
+            return Some((a, b, c, d));
+        }
+        if !fill {
+            return Some((a, b, a + c, b + d));
+        }
+        // 以前のfillrectは x..x+width（右端・下端を含まない）
+        if c <= 0 || d <= 0 {
+            return None;
+        }
+        Some((a, b, a + c - 1, b + d - 1))
+    }
+
+    // 以下は変換 (translate/scale/rotate) を適用して描く。Luaのメソッドから呼ぶ
+    // 平行移動だけなら整数座標の描画をずらして使い、それ以外は多角形にしてから変換する
+
+    // 変換が平行移動だけなら整数の移動量（移動した座標がi32に収まらないときは一般の変換で描くのでNone）
+    fn int_offset(&self, points: &[(i32, i32)]) -> Option<(i32, i32)> {
+        let (dx, dy) = self.state().transform.offset()?;
+        let (dx, dy) = (dx.round(), dy.round());
+        let fits = |v: f64| v >= i32::MIN as f64 && v <= i32::MAX as f64;
+        if points.iter().all(|&(x, y)| fits(x as f64 + dx) && fits(y as f64 + dy)) {
+            Some((dx as i32, dy as i32))
+        } else {
+            None
+        }
+    }
+
+    fn draw_point(&mut self, x: f64, y: f64, color: Rgba) {
+        let (x, y) = self.state().transform.apply(x, y);
+        self.point(x.round() as i32, y.round() as i32, color);
+    }
+
+    fn draw_line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Rgba) {
+        let t = self.state().transform;
+        let (x0, y0) = t.apply(x0, y0);
+        let (x1, y1) = t.apply(x1, y1);
+        self.stroke_line(x0, y0, x1, y1, color);
+    }
+
+    fn draw_circle(&mut self, cx: f64, cy: f64, radius: f64, color: Rgba) {
+        let t = self.state().transform;
+        if t.is_uniform() {
+            let (cx, cy) = t.apply(cx, cy);
+            self.stroke_circle(cx, cy, radius * t.scale_factor(), color);
+        } else {
+            self.draw_ellipse_outline(cx, cy, radius, radius, color);
+        }
+    }
+
+    fn draw_fill_circle(&mut self, cx: f64, cy: f64, radius: f64, color: Rgba) {
+        let t = self.state().transform;
+        if t.is_uniform() {
+            let (cx, cy) = t.apply(cx, cy);
+            self.fill_disc(cx, cy, radius * t.scale_factor(), color);
+        } else {
+            self.draw_ellipse_area(cx, cy, radius, radius, color);
+        }
+    }
+
+    fn draw_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, color: Rgba) {
+        let t = self.state().transform;
+        if let Some((dx, dy)) = self.int_offset(&[(x1, y1), (x2, y2)]) {
+            self.rect(x1 + dx, y1 + dy, x2 + dx, y2 + dy, color);
+            return;
+        }
+        let (x1, y1, x2, y2) = (x1 as f64, y1 as f64, x2 as f64, y2 as f64);
+        self.polygon(&t.apply_all(&[(x1, y1), (x2, y1), (x2, y2), (x1, y2)]), color);
+    }
+
+    fn draw_fill_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, color: Rgba) {
+        let t = self.state().transform;
+        if let Some((dx, dy)) = self.int_offset(&[(x1, y1), (x2, y2)]) {
+            self.fill_rect(x1 + dx, y1 + dy, x2 + dx, y2 + dy, color);
+            return;
+        }
+        // 両端のピクセルを含む範囲
+        let (l, r) = (x1.min(x2) as f64 - 0.5, x1.max(x2) as f64 + 0.5);
+        let (top, bottom) = (y1.min(y2) as f64 - 0.5, y1.max(y2) as f64 + 0.5);
+        self.fill_polygon(&t.apply_all(&[(l, top), (r, top), (r, bottom), (l, bottom)]), color);
+    }
+
+    fn draw_ellipse(&mut self, cx: i32, cy: i32, rx: i32, ry: i32, color: Rgba) {
+        if let Some((dx, dy)) = self.int_offset(&[(cx, cy)]) {
+            self.ellipse(cx + dx, cy + dy, rx, ry, color);
+        } else if rx >= 0 && ry >= 0 {
+            self.draw_ellipse_outline(cx as f64, cy as f64, rx as f64, ry as f64, color);
+        }
+    }
+
+    fn draw_fill_ellipse(&mut self, cx: i32, cy: i32, rx: i32, ry: i32, color: Rgba) {
+        if let Some((dx, dy)) = self.int_offset(&[(cx, cy)]) {
+            self.fill_ellipse(cx + dx, cy + dy, rx, ry, color);
+        } else if rx >= 0 && ry >= 0 {
+            self.draw_ellipse_area(cx as f64, cy as f64, rx as f64, ry as f64, color);
+        }
+    }
+
+    // 楕円の枠を折れ線にして描く
+    fn draw_ellipse_outline(&mut self, cx: f64, cy: f64, rx: f64, ry: f64, color: Rgba) {
+        use std::f64::consts::TAU;
+        let segments = self.arc_segments(rx.max(ry), TAU);
+        let mut points = arc_points(cx, cy, rx, ry, 0.0, TAU, segments);
+        points.pop();
+        let points = self.state().transform.apply_all(&points);
+        self.polygon(&points, color);
+    }
+
+    // 楕円を多角形にして塗る（枠のピクセルまで含むように半ピクセル大きくする）
+    fn draw_ellipse_area(&mut self, cx: f64, cy: f64, rx: f64, ry: f64, color: Rgba) {
+        use std::f64::consts::TAU;
+        let segments = self.arc_segments(rx.max(ry) + 0.5, TAU);
+        let points = arc_points(cx, cy, rx + 0.5, ry + 0.5, 0.0, TAU, segments);
+        let points = self.state().transform.apply_all(&points);
+        self.fill_polygon(&points, color);
+    }
+
+    fn draw_polygon(&mut self, points: &[(f64, f64)], color: Rgba) {
+        let points = self.state().transform.apply_all(points);
+        self.polygon(&points, color);
+    }
+
+    fn draw_fill_polygon(&mut self, points: &[(f64, f64)], color: Rgba) {
+        let points = self.state().transform.apply_all(points);
+        self.fill_polygon(&points, color);
+    }
+
+    fn draw_arc(&mut self, cx: i32, cy: i32, radius: i32, start: f64, end: f64, color: Rgba) {
+        let t = self.state().transform;
+        if let Some((dx, dy)) = self.int_offset(&[(cx, cy)]) {
+            self.arc(cx + dx, cy + dy, radius, start, end, color);
+            return;
+        }
+        if radius < 0 {
+            return;
+        }
+        let sweep = (end - start).clamp(-std::f64::consts::TAU, std::f64::consts::TAU);
+        let segments = self.arc_segments(radius as f64, sweep);
+        let points = arc_points(cx as f64, cy as f64, radius as f64, radius as f64, start, start + sweep, segments);
+        let points = t.apply_all(&points);
+        for pair in points.windows(2) {
+            self.stroke_line(pair[0].0, pair[0].1, pair[1].0, pair[1].1, color);
+        }
+    }
+
+    fn draw_pie(&mut self, cx: i32, cy: i32, radius: i32, start: f64, end: f64, color: Rgba) {
+        let t = self.state().transform;
+        if let Some((dx, dy)) = self.int_offset(&[(cx, cy)]) {
+            self.pie(cx + dx, cy + dy, radius, start, end, color);
+            return;
+        }
+        if radius < 0 {
+            return;
+        }
+        let sweep = (end - start).clamp(-std::f64::consts::TAU, std::f64::consts::TAU);
+        let r = radius as f64 + 0.5;
+        let segments = self.arc_segments(r, sweep);
+        let mut points = vec![(cx as f64, cy as f64)];
+        points.extend(arc_points(cx as f64, cy as f64, r, r, start, start + sweep, segments));
+        self.fill_polygon(&t.apply_all(&points), color);
+    }
+
+    fn draw_round_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, radius: i32, color: Rgba) {
+        let t = self.state().transform;
+        if let Some((dx, dy)) = self.int_offset(&[(x1, y1), (x2, y2)]) {
+            self.round_rect(x1 + dx, y1 + dy, x2 + dx, y2 + dy, radius, color);
+            return;
+        }
+        let (x1, x2) = (x1.min(x2), x1.max(x2));
+        let (y1, y2) = (y1.min(y2), y1.max(y2));
+        let radius = (radius as i64).min((x2 as i64 - x1 as i64) / 2).min((y2 as i64 - y1 as i64) / 2).max(0) as f64;
+        let segments = self.arc_segments(radius, std::f64::consts::FRAC_PI_2);
+        let points = round_rect_points(x1 as f64, y1 as f64, x2 as f64, y2 as f64, radius, segments);
+        self.polygon(&t.apply_all(&points), color);
+    }
+
+    fn draw_fill_round_rect(&mut self, x1: i32, y1: i32, x2: i32, y2: i32, radius: i32, color: Rgba) {
+        let t = self.state().transform;
+        if let Some((dx, dy)) = self.int_offset(&[(x1, y1), (x2, y2)]) {
+            self.fill_round_rect(x1 + dx, y1 + dy, x2 + dx, y2 + dy, radius, color);
+            return;
+        }
+        let (x1, x2) = (x1.min(x2), x1.max(x2));
+        let (y1, y2) = (y1.min(y2), y1.max(y2));
+        let radius = (radius as i64).min((x2 as i64 - x1 as i64) / 2).min((y2 as i64 - y1 as i64) / 2).max(0) as f64 + 0.5;
+        let segments = self.arc_segments(radius, std::f64::consts::FRAC_PI_2);
+        let points = round_rect_points(x1 as f64 - 0.5, y1 as f64 - 0.5, x2 as f64 + 0.5, y2 as f64 + 0.5, radius, segments);
+        self.fill_polygon(&t.apply_all(&points), color);
+    }
+
+    // 塗りはじめの点だけ変換する（塗る範囲は境界色で決まる）
+    fn draw_paint(&mut self, x: i32, y: i32, fill: Rgba, boundary: Rgba) -> usize {
+        let (x, y) = self.state().transform.apply(x as f64, y as f64);
+        self.paint(x.round() as i32, y.round() as i32, fill, boundary)
+    }
+}
+
+// text の可変長引数を空白区切りの文字列にする
+fn join_text_args(args: &Variadic<mlua::Value>) -> String {
+    args.iter()
+        .map(|v| match v {
+            mlua::Value::String(s) => s.to_str().unwrap_or("").to_owned(),
+            mlua::Value::Integer(i) => i.to_string(),
+            mlua::Value::Number(f) => f.to_string(),
+            mlua::Value::Boolean(b) => b.to_string(),
+            _ => "".to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// 座標のテーブルを読む: {x1, y1, x2, y2, ...} か {{x, y}, ...} / {{x = x, y = y}, ...}
+fn read_points(table: &mlua::Table) -> mlua::Result<Vec<(f64, f64)>> {
+    let values = table.clone().sequence_values::<mlua::Value>().collect::<mlua::Result<Vec<_>>>()?;
+    if let Some(mlua::Value::Table(_)) = values.first() {
+        let points = values
+            .into_iter()
+            .map(|v| match v {
+                mlua::Value::Table(p) => match p.get::<_, Option<f64>>("x")? {
+                    Some(x) => Ok((x, p.get("y")?)),
+                    None => Ok((p.get(1)?, p.get(2)?)),
+                },
+                _ => Err(mlua::Error::external("points must be all tables or all numbers")),
+            })
+            .collect::<mlua::Result<Vec<(f64, f64)>>>()?;
+        check_finite(&points.iter().flat_map(|&(x, y)| [x, y]).collect::<Vec<_>>())?;
+        return Ok(points);
+    }
+    if values.len() % 2 != 0 {
+        return Err(mlua::Error::external("points table must have an even number of coordinates"));
+    }
+    let coords = table.clone().sequence_values::<f64>().collect::<mlua::Result<Vec<_>>>()?;
+    check_finite(&coords)?;
+    Ok(coords.chunks_exact(2).map(|c| (c[0], c[1])).collect())
+}
+
+// フォントの列を読む: {path, {path, index}, ...}（{path = path, index = index} も可）
+fn read_font_specs(table: &mlua::Table) -> mlua::Result<Vec<FontSpec>> {
+    table
+        .clone()
+        .sequence_values::<mlua::Value>()
+        .map(|v| match v? {
+            mlua::Value::String(path) => Ok((path.to_str()?.to_owned(), 0)),
+            mlua::Value::Table(spec) => match spec.get::<_, Option<String>>("path")? {
+                Some(path) => Ok((path, spec.get::<_, Option<u32>>("index")?.unwrap_or(0))),
+                None => Ok((spec.get(1)?, spec.get::<_, Option<u32>>(2)?.unwrap_or(0))),
+            },
+            _ => Err(mlua::Error::external("fallback fonts must be paths or {path, index} tables")),
+        })
+        .collect()
+}
+
+// blitの転送元（ウィンドウかgraphicバッファ）から範囲を切り出す
+// 借用できないのは自分自身を転送元にした場合なので this から切り出す
+fn read_source_region<T: Canvas>(
+    this: &T,
+    src: &mlua::AnyUserData,
+    sx: i32,
+    sy: i32,
+    sw: Option<i32>,
+    sh: Option<i32>,
+) -> mlua::Result<PixelRegion> {
+    // sw, sh を省略すると転送元の (sx, sy) から右下まで
+    let copy = |canvas: &dyn Canvas| {
+        let w = sw.unwrap_or(canvas.width() as i32 - sx);
+        let h = sh.unwrap_or(canvas.height() as i32 - sy);
+        canvas.copy_region(sx, sy, w, h)
+    };
+    match src.borrow::<crate::luagraphic::RGBABufferBase>() {
+        Ok(buf) => return Ok(copy(&*buf)),
+        Err(mlua::Error::UserDataBorrowError) => return Ok(copy(this)),
+        Err(mlua::Error::UserDataTypeMismatch) => {}
+        Err(e) => return Err(e),
+    }
+    match src.borrow::<std::sync::Arc<Mutex<crate::luamod::LuaWindow>>>() {
+        Ok(win) => match win.try_lock() {
+            Ok(win) => Ok(copy(&*win)),
+            // ロック中なら自分自身
+            Err(_) => Ok(copy(this)),
+        },
+        Err(mlua::Error::UserDataBorrowError) => Ok(copy(this)),
+        Err(mlua::Error::UserDataTypeMismatch) => Err(mlua::Error::external("blit source must be a window or graphic buffer")),
+        Err(e) => Err(e),
+    }
+}
+
+// Canvasを実装した型に共通のLuaメソッドを登録する
+pub fn add_canvas_methods<'lua, T: Canvas, M: UserDataMethods<'lua, T>>(methods: &mut M) {
+    // #region image methods
+    // drawimage: w:drawimage(img, x, y, img_sx, img_sy, img_dx, img_dy)
+    methods.add_method_mut(
+        "drawimage",
+        |_, this, (img, x, y, img_sx, img_sy, img_dx, img_dy): (mlua::AnyUserData, i32, i32, Option<u32>, Option<u32>, Option<u32>, Option<u32>)| {
+            let img = img.borrow::<LuaImage>()?;
+            let width = img_dx.unwrap_or(img.img.width());
+            let height = img_dy.unwrap_or(img.img.height());
+            this.draw_image(&img.img, x, y, (img_sx.unwrap_or(0), img_sy.unwrap_or(0), width, height));
+            Ok(())
+        },
+    );
+    // drawimageex: w:drawimageex(src, x, y, {angle, sx, sy, ox, oy, flipx, flipy, alpha, filter, src = {x, y, w, h}})
+    // src は画像・ウィンドウ・graphicバッファ。画像の (ox, oy) を (x, y) に置き、そこを中心に拡大・回転する
+    methods.add_method_mut(
+        "drawimageex",
+        |_, this, (src, x, y, opts): (mlua::AnyUserData, f64, f64, Option<mlua::Table>)| {
+            let opts = match opts {
+                Some(table) => DrawImageOptions::from_table(&table)?,
+                None => DrawImageOptions::default(),
+            };
+            let (sx, sy, sw, sh) = opts.src.unwrap_or((0, 0, None, None));
+            let region = if let Ok(img) = src.borrow::<LuaImage>() {
+                PixelRegion::from_image(&img.img, sx, sy, sw, sh)
+            } else if src.is::<crate::luagraphic::RGBABufferBase>() || src.is::<std::sync::Arc<Mutex<crate::luamod::LuaWindow>>>() {
+                read_source_region(this, &src, sx, sy, sw, sh)?
+            } else {
+                return Err(mlua::Error::external("drawimageex source must be an image, window or graphic buffer"));
+            };
+            this.draw_image_ex(&region, x, y, &opts);
+            Ok(())
+        },
+    );
+    // captureimage: w:captureimage(x, y, width, height)
+    methods.add_method("captureimage", |_, this, (x, y, width, height): (i32, i32, u32, u32)| {
+        Ok(LuaImage { img: this.capture_image(x, y, width, height) })
+    });
+    // toimage: w:toimage() キャンバス全体を画像にする
+    methods.add_method("toimage", |_, this, ()| {
+        Ok(LuaImage { img: this.capture_image(0, 0, this.width() as u32, this.height() as u32) })
+    });
+    // blit: dst:blit(src, dx, dy, sx, sy, sw, sh, opts) ウィンドウ・graphicバッファ間で直接転送する
+    // opts = {blend = true, colorkey = {r, g, b}, opacity = 1.0}
+    methods.add_method_mut(
+        "blit",
+        |_, this, (src, dx, dy, sx, sy, sw, sh, opts): (mlua::AnyUserData, Option<i32>, Option<i32>, Option<i32>, Option<i32>, Option<i32>, Option<i32>, Option<mlua::Table>)| {
+            let opts = match opts {
+                Some(table) => BlitOptions::from_table(&table)?,
+                None => BlitOptions::default(),
+            };
+            let (sx, sy) = (sx.unwrap_or(0), sy.unwrap_or(0));
+            let region = read_source_region(this, &src, sx, sy, sw, sh)?;
+            // 転送元の範囲外を除いた分だけ転送先もずらす
+            let dx = dx.unwrap_or(0) + region.x - sx;
+            let dy = dy.unwrap_or(0) + region.y - sy;
+            this.draw_region(&region, dx, dy, &opts);
+            Ok(())
+        },
+    );
+    // #endregion image methods
+
+    // #region graphic methods
+    // cls/clear: 全体（クリップ中はクリップ範囲）を塗りつぶす（色を省略すると黒）
+    let clear = |_: &'lua mlua::Lua, this: &mut T, (r, g, b, a): (Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+        this.clear((r.unwrap_or(0), g.unwrap_or(0), b.unwrap_or(0), a.unwrap_or(255)));
+        Ok(())
+    };
+    methods.add_method_mut("cls", clear);
+    methods.add_method_mut("clear", clear);
+    methods.add_method_mut(
+        "point",
+        |_, this, (x, y, r, g, b, a): (f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            check_finite(&[x, y])?;
+            let color = this.color(r, g, b, a);
+            this.draw_point(x, y, color);
+            Ok(())
+        },
+    );
+    methods.add_method("getpoint", |_, this, (x, y): (i32, i32)| {
+        Ok(this.get_point(x, y))
+    });
+    methods.add_method(
+        "getpointi",
+        |lua, this, (x, y, table,): (i32, i32, Option<mlua::Table>,)| {
+            let table = match table {
+                Some(t) => t,
+                None => lua.create_table()?,
+            };
+            let (r, g, b, a) = this.get_point(x, y);
+            table.set(1, r)?;
+            table.set(2, g)?;
+            table.set(3, b)?;
+            table.set(4, a)?;
+            Ok(table)
+        },
+    );
+    methods.add_method_mut(
+        "line",
+        |_, this, (x0, y0, x1, y1, r, g, b, a): (f64, f64, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            check_finite(&[x0, y0, x1, y1])?;
+            let color = this.color(r, g, b, a);
+            this.draw_line(x0, y0, x1, y1, color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "circle",
+        |_, this, (cx, cy, radius, r, g, b, a): (f64, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            check_finite(&[cx, cy, radius])?;
+            let color = this.color(r, g, b, a);
+            this.draw_circle(cx, cy, radius, color);
+            Ok(())
+        },
+    );
+    // rect: w:rect(x1, y1, x2, y2, r, g, b, a)（互換モードでは (x, y, width, height, ...)）
+    methods.add_method_mut(
+        "rect",
+        |_, this, (x1, y1, x2, y2, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            if let Some((x1, y1, x2, y2)) = this.rect_corners(x1, y1, x2, y2, false) {
+                this.draw_rect(x1, y1, x2, y2, color);
+            }
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "fillrect",
+        |_, this, (x1, y1, x2, y2, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            if let Some((x1, y1, x2, y2)) = this.rect_corners(x1, y1, x2, y2, true) {
+                this.draw_fill_rect(x1, y1, x2, y2, color);
+            }
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "fillcircle",
+        |_, this, (cx, cy, radius, r, g, b, a): (f64, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            check_finite(&[cx, cy, radius])?;
+            let color = this.color(r, g, b, a);
+            this.draw_fill_circle(cx, cy, radius, color);
+            Ok(())
+        },
+    );
+    // ellipse / fillellipse: w:ellipse(cx, cy, rx, ry, r, g, b, a)
+    methods.add_method_mut(
+        "ellipse",
+        |_, this, (cx, cy, rx, ry, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_ellipse(cx, cy, rx, ry, color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "fillellipse",
+        |_, this, (cx, cy, rx, ry, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_fill_ellipse(cx, cy, rx, ry, color);
+            Ok(())
+        },
+    );
+    // triangle / filltriangle: w:triangle(x1, y1, x2, y2, x3, y3, r, g, b, a)
+    methods.add_method_mut(
+        "triangle",
+        |_, this, (x1, y1, x2, y2, x3, y3, r, g, b, a): (f64, f64, f64, f64, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            check_finite(&[x1, y1, x2, y2, x3, y3])?;
+            let color = this.color(r, g, b, a);
+            this.draw_polygon(&[(x1, y1), (x2, y2), (x3, y3)], color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "filltriangle",
+        |_, this, (x1, y1, x2, y2, x3, y3, r, g, b, a): (f64, f64, f64, f64, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            check_finite(&[x1, y1, x2, y2, x3, y3])?;
+            let color = this.color(r, g, b, a);
+            this.draw_fill_polygon(&[(x1, y1), (x2, y2), (x3, y3)], color);
+            Ok(())
+        },
+    );
+    // polygon / fillpolygon: w:polygon({x1, y1, x2, y2, ...}, r, g, b, a)（{{x, y}, ...} も可）
+    methods.add_method_mut(
+        "polygon",
+        |_, this, (points, r, g, b, a): (mlua::Table, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_polygon(&read_points(&points)?, color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "fillpolygon",
+        |_, this, (points, r, g, b, a): (mlua::Table, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_fill_polygon(&read_points(&points)?, color);
+            Ok(())
+        },
+    );
+    // arc / pie: w:arc(cx, cy, radius, start, end, r, g, b, a) 角度はラジアン、0が右で時計回り
+    methods.add_method_mut(
+        "arc",
+        |_, this, (cx, cy, radius, start, end, r, g, b, a): (i32, i32, i32, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_arc(cx, cy, radius, start, end, color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "pie",
+        |_, this, (cx, cy, radius, start, end, r, g, b, a): (i32, i32, i32, f64, f64, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_pie(cx, cy, radius, start, end, color);
+            Ok(())
+        },
+    );
+    // roundrect / fillroundrect: w:roundrect(x1, y1, x2, y2, radius, r, g, b, a)
+    methods.add_method_mut(
+        "roundrect",
+        |_, this, (x1, y1, x2, y2, radius, r, g, b, a): (i32, i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_round_rect(x1, y1, x2, y2, radius, color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "fillroundrect",
+        |_, this, (x1, y1, x2, y2, radius, r, g, b, a): (i32, i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let color = this.color(r, g, b, a);
+            this.draw_fill_round_rect(x1, y1, x2, y2, radius, color);
+            Ok(())
+        },
+    );
+    methods.add_method_mut(
+        "scroll",
+        |_, this, (dx, dy, r, g, b, a): (i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            this.scroll(dx, dy, (r.unwrap_or(0), g.unwrap_or(0), b.unwrap_or(0), a.unwrap_or(255)));
+            Ok(())
+        },
+    );
+    // paint: w:paint(x, y, r, g, b[, a, sr, sg, sb, sa]) 境界色(省略時は塗りつぶし色)まで塗り、塗ったピクセル数を返す
+    methods.add_method_mut(
+        "paint",
+        |_, this, (x, y, r, g, b, a, sr, sg, sb, sa): (i32, i32, u8, u8, u8, Option<u8>, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+            let a = a.unwrap_or(255);
+            let boundary = (sr.unwrap_or(r), sg.unwrap_or(g), sb.unwrap_or(b), sa.unwrap_or(a));
+            let count = this.draw_paint(x, y, (r, g, b, a), boundary);
+            Ok(if this.state().compat { None } else { Some(count) })
+        },
+    );
+    // setlinewidth: w:setlinewidth(width) line/circle/rect/triangle/polygonの線の太さ（デフォルト1）
+    methods.add_method_mut("setlinewidth", |_, this, width: f64| {
+        if width.is_nan() || width <= 0.0 {
+            return Err(mlua::Error::external("line width must be positive"));
+        }
+        this.state_mut().line_width = width;
+        Ok(())
+    });
+    methods.add_method("getlinewidth", |_, this, ()| {
+        Ok(this.state().line_width)
+    });
+    // setantialias: w:setantialias(true) line/circle/fillcircleをアンチエイリアスで描く
+    methods.add_method_mut("setantialias", |_, this, antialias: bool| {
+        this.state_mut().antialias = antialias;
+        Ok(())
+    });
+    methods.add_method("getantialias", |_, this, ()| {
+        Ok(this.state().antialias)
+    });
+    // setblend: w:setblend(mode) 合成方法 "replace" / "alpha"（デフォルト）/ "add" / "subtract" / "multiply" / "screen" / "xor" / "min" / "max"
+    methods.add_method_mut("setblend", |_, this, mode: String| {
+        this.state_mut().blend = BlendMode::from_name(&mode)?;
+        Ok(())
+    });
+    methods.add_method("getblend", |_, this, ()| {
+        Ok(this.state().blend.name())
+    });
+    // #endregion graphic methods
+
+    // #region clip/transform methods
+    // setclip: w:setclip(x, y, width, height) この範囲の外には描かない（キャンバスの座標、変換の影響は受けない）
+    methods.add_method_mut("setclip", |_, this, (x, y, width, height): (i32, i32, i32, i32)| {
+        this.state_mut().clip = Some((x, y, x + width.max(0), y + height.max(0)));
+        Ok(())
+    });
+    methods.add_method_mut("resetclip", |_, this, ()| {
+        this.state_mut().clip = None;
+        Ok(())
+    });
+    // getclip: w:getclip() -> x, y, width, height（クリップしていなければnil）
+    methods.add_method("getclip", |_, this, ()| {
+        Ok(match this.state().clip {
+            Some((x0, y0, x1, y1)) => (Some(x0), Some(y0), Some(x1 - x0), Some(y1 - y0)),
+            None => (None, None, None, None),
+        })
+    });
+    // push / pop: 変換とクリップを保存・復元する
+    methods.add_method_mut("push", |_, this, ()| {
+        let state = this.state_mut();
+        if state.saved.len() >= MAX_SAVED_STATES {
+            return Err(mlua::Error::external("push: too many saved states (missing pop?)"));
+        }
+        state.saved.push((state.transform, state.clip));
+        Ok(())
+    });
+    methods.add_method_mut("pop", |_, this, ()| {
+        let state = this.state_mut();
+        match state.saved.pop() {
+            Some((transform, clip)) => {
+                state.transform = transform;
+                state.clip = clip;
+                Ok(())
+            }
+            None => Err(mlua::Error::external("pop: no saved state (pop without push)")),
+        }
+    });
+    // translate / scale / rotate: 以降の描画の座標を変換する（後から指定した変換が先に適用される）
+    methods.add_method_mut("translate", |_, this, (dx, dy): (f64, f64)| {
+        let state = this.state_mut();
+        state.transform = state.transform.multiply(Transform::translate(dx, dy));
+        Ok(())
+    });
+    // scale: w:scale(sx[, sy]) syを省略するとsxと同じ
+    methods.add_method_mut("scale", |_, this, (sx, sy): (f64, Option<f64>)| {
+        let state = this.state_mut();
+        state.transform = state.transform.multiply(Transform::scale(sx, sy.unwrap_or(sx)));
+        Ok(())
+    });
+    // rotate: w:rotate(angle) ラジアン、時計回り
+    methods.add_method_mut("rotate", |_, this, angle: f64| {
+        let state = this.state_mut();
+        state.transform = state.transform.multiply(Transform::rotate(angle));
+        Ok(())
+    });
+    methods.add_method_mut("resettransform", |_, this, ()| {
+        this.state_mut().transform = Transform::IDENTITY;
+        Ok(())
+    });
+    // #endregion clip/transform methods
+
+    // #region text methods
+    methods.add_method_mut("settextcolor", |_, this, (r, g, b, a): (Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
+        this.state_mut().text_color = (r.unwrap_or(255), g.unwrap_or(255), b.unwrap_or(255), a.unwrap_or(255));
+        Ok(())
+    });
+    methods.add_method("gettextcolor", |_, this, ()| {
+        Ok(this.state().text_color)
+    });
+    methods.add_method_mut("settextfontsize", |_, this, size: usize| {
+        if size == 0 {
+            return Err(mlua::Error::external("font size must be positive"));
+        }
+        this.state_mut().text_font_size = size;
+        Ok(())
+    });
+    methods.add_method("gettextfontsize", |_, this, ()| {
+        Ok(this.state().text_font_size)
+    });
+    // setfontpath: w:setfontpath(path[, index]) index はフォントコレクション (.ttc) の中の番号（0から）
+    // 読み込めないフォントはエラーになり、設定は変わらない
+    methods.add_method_mut("setfontpath", |_, this, (path, index): (String, Option<u32>)| {
+        let index = index.unwrap_or(0);
+        get_font(&path, index).map_err(mlua::Error::external)?;
+        this.state_mut().fontpath = path;
+        this.state_mut().fontindex = index;
+        Ok(())
+    });
+    methods.add_method("getfontpath", |_, this, ()| {
+        Ok((this.state().fontpath.clone(), this.state().fontindex))
+    });
+    // setfallbackfonts: w:setfallbackfonts({path, {path, index}, ...}) 主フォントにない文字（絵文字・記号など）を順に探すフォント
+    // nil か空のテーブルで解除
+    methods.add_method_mut("setfallbackfonts", |_, this, fonts: Option<mlua::Table>| {
+        let specs = match fonts {
+            Some(table) => read_font_specs(&table)?,
+            None => Vec::new(),
+        };
+        get_fonts(&specs).map_err(mlua::Error::external)?;
+        this.state_mut().fallback_fonts = specs;
+        Ok(())
+    });
+    // getfallbackfonts: w:getfallbackfonts() -> {{path, index}, ...}
+    methods.add_method("getfallbackfonts", |lua, this, ()| {
+        let list = lua.create_table()?;
+        for (i, (path, index)) in this.state().fallback_fonts.iter().enumerate() {
+            let spec = lua.create_table()?;
+            spec.set(1, path.as_str())?;
+            spec.set(2, *index)?;
+            list.set(i + 1, spec)?;
+        }
+        Ok(list)
+    });
+    // textmetrics: w:textmetrics(...) -> width, height, bx, by, bw, bh（textと同じ引数）
+    // width, height は送り幅と行の高さ、bx〜bh は実際に描かれる範囲（左上からの位置、何も描かれなければnil）
+    methods.add_method("textmetrics", |_, this, args: Variadic<mlua::Value>| {
+        let layout = this.text_layout(&join_text_args(&args))?;
+        let (bx, by, bw, bh) = match layout.bounds {
+            Some((x0, y0, x1, y1)) => (Some(x0), Some(y0), Some(x1 - x0), Some(y1 - y0)),
+            None => (None, None, None, None),
+        };
+        Ok((layout.width, layout.height, bx, by, bw, bh))
+    });
+    // text: w:text(x, y, ...) 引数は空白区切りで連結、描画した幅・高さを返す
+    methods.add_method_mut("text", |_, this, (x, y, args): (i32, i32, Variadic<mlua::Value>)| {
+        this.text(x, y, &join_text_args(&args))
+    });
+    // textbox: w:textbox(x, y, width, height, str, {align = "left", valign = "top", wrap = "word", linespacing = 1.0, ellipsis = true})
+    // 改行・折り返し（日本語は文字の間でも折り返す）・寄せを行って描き、width, height, 行数, 省略したか を返す
+    // width/height を nil にすると折り返さない・行数を制限しない
+    methods.add_method_mut(
+        "textbox",
+        |_, this, (x, y, width, height, text, opts): (i32, i32, Option<i32>, Option<i32>, String, Option<mlua::Table>)| {
+            let opts = match opts {
+                Some(table) => TextBoxOptions::from_table(&table)?,
+                None => TextBoxOptions::default(),
+            };
+            this.text_box(x, y, width, height, &text, &opts)
+        },
+    );
+    // #endregion text methods
+
+    // #region metric methods
+    methods.add_method("getwidth", |_, this, ()| {
+        Ok(this.width())
+    });
+    methods.add_method("getheight", |_, this, ()| {
+        Ok(this.height())
+    });
+    // setcompat: w:setcompat(true) 以前のgraphicバッファの規則にする
+    methods.add_method_mut("setcompat", |_, this, compat: bool| {
+        this.state_mut().compat = compat;
+        Ok(())
+    });
+    methods.add_method("getcompat", |_, this, ()| {
+        Ok(this.state().compat)
+    });
+    // #endregion metric methods
+}
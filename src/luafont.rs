@@ -0,0 +1,305 @@
+//! テキスト描画用のフォントとグリフのキャッシュ、レイアウト（1行・折り返し）
+//! - グリフは (フォント, サイズ, 文字) ごとに一度だけラスタライズして使い回す
+//! - 文字の送り幅・カーニング・行の高さはフォントの値を使う
+//! - フォントは最初のフォント（主フォント）と代替フォントの列で指定し、主フォントにない文字は代替フォントから探す
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use unicode_width::UnicodeWidthChar;
+
+pub const DEFAULT_FONT_PATH: &str = "assets/fonts.ttf";
+
+// キャッシュするグリフの上限（超えたら全部捨てて作り直す）
+const MAX_CACHED_GLYPHS: usize = 8192;
+
+// 読み込んだまま保持するフォントの上限（超えたら最も長く使っていないフォントを捨てる）
+const MAX_CACHED_FONTS: usize = 16;
+
+static FONTS: OnceLock<Mutex<FontCache>> = OnceLock::new();
+
+static GLYPHS: OnceLock<Mutex<HashMap<GlyphKey, Arc<Glyph>>>> = OnceLock::new();
+
+// フォントファイルのパスと、フォントコレクション (.ttc) の中の番号（.ttfは0）
+pub type FontSpec = (String, u32);
+
+// 読み込んだフォントと最後に使った順番
+struct FontCache {
+    fonts: HashMap<FontSpec, (Arc<fontdue::Font>, u64)>,
+    clock: u64,
+}
+
+impl FontCache {
+    // 上限を超えていれば、最も長く使っていないフォントから捨てる
+    // 他で使用中（キャッシュ以外にも参照がある）のフォントは捨てない
+    fn evict(&mut self) {
+        while self.fonts.len() > MAX_CACHED_FONTS {
+            let oldest = self
+                .fonts
+                .iter()
+                .filter(|(_, (font, _))| Arc::strong_count(font) == 1)
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(spec, _)| spec.clone());
+            let spec = match oldest {
+                Some(spec) => spec,
+                None => break,
+            };
+            if let Some((font, _)) = self.fonts.remove(&spec) {
+                forget_glyphs(&font);
+            }
+        }
+    }
+}
+
+// フォントは (パス, 番号) ごとに一度だけ読み込み、最近使った MAX_CACHED_FONTS 個まで保持する
+// 読み込めなければエラーメッセージを返す（読み込みに失敗したものは保持しない）
+pub fn get_font(fontpath: &str, index: u32) -> Result<Arc<fontdue::Font>, String> {
+    let cache_mutex = FONTS.get_or_init(|| Mutex::new(FontCache { fonts: HashMap::new(), clock: 0 }));
+    let mut cache = cache_mutex.lock().unwrap();
+    cache.clock += 1;
+    let clock = cache.clock;
+    let spec = (fontpath.to_string(), index);
+    if let Some((font, used)) = cache.fonts.get_mut(&spec) {
+        *used = clock;
+        return Ok(font.clone());
+    }
+    let data = std::fs::read(fontpath).map_err(|e| format!("cannot read font file: {} ({})", fontpath, e))?;
+    let settings = fontdue::FontSettings { collection_index: index, ..fontdue::FontSettings::default() };
+    let font = fontdue::Font::from_bytes(data, settings).map_err(|e| format!("font load failed: {} #{} ({})", fontpath, index, e))?;
+    let font = Arc::new(font);
+    cache.fonts.insert(spec, (font.clone(), clock));
+    cache.evict();
+    Ok(font)
+}
+
+// 主フォントと代替フォントをまとめて読み込む
+pub fn get_fonts(specs: &[FontSpec]) -> Result<Vec<Arc<fontdue::Font>>, String> {
+    specs.iter().map(|(path, index)| get_font(path, *index)).collect()
+}
+
+// 文字を描くフォント（主フォントから順に探し、どれにもなければ主フォント）
+fn font_index_for(fonts: &[Arc<fontdue::Font>], ch: char) -> usize {
+    fonts.iter().position(|font| font.lookup_glyph_index(ch) != 0).unwrap_or(0)
+}
+
+// フォントのアドレス（フォントを捨てるときにそのグリフも捨てるので一意）、サイズ、文字
+type GlyphKey = (usize, u32, char);
+
+// 捨てるフォントのグリフを消す（同じアドレスに読み込んだ別のフォントで使われないように）
+fn forget_glyphs(font: &Arc<fontdue::Font>) {
+    let ptr = Arc::as_ptr(font) as usize;
+    if let Some(glyphs_mutex) = GLYPHS.get() {
+        glyphs_mutex.lock().unwrap().retain(|key, _| key.0 != ptr);
+    }
+}
+
+// ラスタライズしたグリフ
+pub struct Glyph {
+    pub metrics: fontdue::Metrics,
+    pub bitmap: Vec<u8>, // 被覆率 (0〜255)、metrics.width * metrics.height
+}
+
+// 並べたグリフ（x, y はビットマップの左上、テキストの左上からの位置）
+pub struct PlacedGlyph {
+    pub x: i32,
+    pub y: i32,
+    pub glyph: Arc<Glyph>,
+}
+
+// 1行のテキストを並べた結果
+pub struct TextLayout {
+    pub glyphs: Vec<PlacedGlyph>,
+    pub width: usize,  // 送り幅の合計（カーニングを含む）
+    pub height: usize, // 行の高さ (ascent - descent)
+    pub bounds: Option<(i32, i32, i32, i32)>, // 実際に描かれる範囲 (x0, y0, x1, y1)（x1/y1は含まない）
+}
+
+// フォントの行の高さ（ascent, descent, 行送り）。フォントに情報がなければサイズから決める
+pub fn line_metrics(font: &fontdue::Font, size: f32) -> (f32, f32, f32) {
+    match font.horizontal_line_metrics(size) {
+        Some(m) => (m.ascent, m.descent, m.new_line_size),
+        None => (size, 0.0, size),
+    }
+}
+
+// テキストを1行に並べる（改行などの制御文字は描かない）。行の高さは主フォント fonts[0] で決める
+pub fn layout_line(fonts: &[Arc<fontdue::Font>], size: f32, text: &str) -> TextLayout {
+    let (ascent, descent, _) = line_metrics(&fonts[0], size);
+    let baseline = ascent.round() as i32;
+    let glyphs_mutex = GLYPHS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut cache = glyphs_mutex.lock().unwrap();
+    let mut glyphs = Vec::new();
+    let mut bounds: Option<(i32, i32, i32, i32)> = None;
+    let mut pen_x = 0.0f32;
+    let mut prev: Option<(usize, char)> = None;
+    for ch in text.chars().filter(|ch| !ch.is_control()) {
+        let index = font_index_for(fonts, ch);
+        let font = &fonts[index];
+        // カーニングは同じフォントの文字の間だけ
+        if let Some((_, p)) = prev.filter(|(i, _)| *i == index) {
+            pen_x += font.horizontal_kern(p, ch, size).unwrap_or(0.0);
+        }
+        let glyph = glyph(&mut cache, font, size, ch);
+        let m = &glyph.metrics;
+        // 下ぞろえ: ベースラインから高さ分引く
+        let x = pen_x.round() as i32 + m.xmin;
+        let y = baseline - m.height as i32 - m.ymin;
+        if m.width > 0 && m.height > 0 {
+            let (x1, y1) = (x + m.width as i32, y + m.height as i32);
+            bounds = Some(match bounds {
+                Some((bx0, by0, bx1, by1)) => (bx0.min(x), by0.min(y), bx1.max(x1), by1.max(y1)),
+                None => (x, y, x1, y1),
+            });
+        }
+        pen_x += m.advance_width;
+        glyphs.push(PlacedGlyph { x, y, glyph });
+        prev = Some((index, ch));
+    }
+    TextLayout {
+        glyphs,
+        width: pen_x.max(0.0).ceil() as usize,
+        height: (ascent - descent).ceil() as usize,
+        bounds,
+    }
+}
+
+// キャッシュからグリフを取り出す（なければラスタライズして追加）
+fn glyph(cache: &mut HashMap<GlyphKey, Arc<Glyph>>, font: &Arc<fontdue::Font>, size: f32, ch: char) -> Arc<Glyph> {
+    let key = (Arc::as_ptr(font) as usize, size.to_bits(), ch);
+    if let Some(glyph) = cache.get(&key) {
+        return glyph.clone();
+    }
+    if cache.len() >= MAX_CACHED_GLYPHS {
+        cache.clear();
+    }
+    let (metrics, bitmap) = font.rasterize(ch, size);
+    let glyph = Arc::new(Glyph { metrics, bitmap });
+    cache.insert(key, glyph.clone());
+    glyph
+}
+
+// 行の折り返し方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+    None, // 折り返さない
+    Word, // 空白と全角文字の前後で折り返す（入りきらない単語は文字の途中で折り返す）
+    Char, // どの文字の間でも折り返す
+}
+
+// 行頭に置かない文字（禁則処理）
+const NO_LINE_START: &str = ",.!?:;)]}、。，．・：；？！ー」』）】〉》〕｝…‥ぁぃぅぇぉっゃゅょゎァィゥェォッャュョヮヵヶ々ゝゞヽヾ";
+// 行末に置かない文字
+const NO_LINE_END: &str = "([{「『（【〈《〔｛";
+
+// 全角の文字（日本語の文は空白がないので、文字の間で折り返せる）
+fn is_wide(ch: char) -> bool {
+    UnicodeWidthChar::width(ch) == Some(2)
+}
+
+// a と b の間で改行できるか
+fn can_break(a: char, b: char, wrap: Wrap) -> bool {
+    if b == ' ' || a == ' ' {
+        return true;
+    }
+    if NO_LINE_START.contains(b) || NO_LINE_END.contains(a) {
+        return false;
+    }
+    match wrap {
+        Wrap::None => false,
+        Wrap::Word => is_wide(a) || is_wide(b),
+        Wrap::Char => true,
+    }
+}
+
+// 各文字の左端・右端の位置（送り幅とカーニングから）
+fn char_edges(fonts: &[Arc<fontdue::Font>], size: f32, chars: &[char]) -> Vec<(f32, f32)> {
+    let mut edges = Vec::with_capacity(chars.len());
+    let mut pen = 0.0f32;
+    let mut prev: Option<(usize, char)> = None;
+    for &ch in chars {
+        let index = font_index_for(fonts, ch);
+        let font = &fonts[index];
+        if let Some((_, p)) = prev.filter(|(i, _)| *i == index) {
+            pen += font.horizontal_kern(p, ch, size).unwrap_or(0.0);
+        }
+        let left = pen;
+        pen += font.metrics(ch, size).advance_width;
+        edges.push((left, pen));
+        prev = Some((index, ch));
+    }
+    edges
+}
+
+// テキストを改行と幅 max_width で行に分ける（タブは空白、それ以外の制御文字は除く）
+pub fn wrap_text(fonts: &[Arc<fontdue::Font>], size: f32, text: &str, max_width: Option<f32>, wrap: Wrap) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let chars: Vec<char> = paragraph
+            .chars()
+            .map(|ch| if ch == '\t' { ' ' } else { ch })
+            .filter(|ch| !ch.is_control())
+            .collect();
+        match max_width {
+            Some(max_width) if wrap != Wrap::None => wrap_paragraph(fonts, size, &chars, max_width, wrap, &mut lines),
+            _ => lines.push(chars.iter().collect()),
+        }
+    }
+    lines
+}
+
+fn wrap_paragraph(fonts: &[Arc<fontdue::Font>], size: f32, chars: &[char], max_width: f32, wrap: Wrap, lines: &mut Vec<String>) {
+    let edges = char_edges(fonts, size, chars);
+    let mut start = 0;
+    loop {
+        // 入るところまで進める（1文字も入らなくても1文字は置く）
+        let mut end = start;
+        let mut last_break = None;
+        while end < chars.len() {
+            if end > start && can_break(chars[end - 1], chars[end], wrap) {
+                last_break = Some(end);
+            }
+            // 行末の空白ははみ出してもよい（折り返すときに消す）
+            if end > start && chars[end] != ' ' && edges[end].1 - edges[start].0 > max_width {
+                break;
+            }
+            end += 1;
+        }
+        if end == chars.len() {
+            lines.push(chars[start..].iter().collect::<String>().trim_end().to_string());
+            return;
+        }
+        // 折り返せる位置がなければ、入りきらない文字の前で折り返す
+        let brk = match last_break {
+            Some(b) => b,
+            None => end,
+        };
+        lines.push(chars[start..brk].iter().collect::<String>().trim_end().to_string());
+        // 折り返した行頭の空白は詰める
+        start = brk;
+        while start < chars.len() && chars[start] == ' ' {
+            start += 1;
+        }
+        if start == chars.len() {
+            return;
+        }
+    }
+}
+
+// 幅 max_width に入るように末尾を省略記号にする（force なら入っていても省略記号を付ける）
+pub fn ellipsize(fonts: &[Arc<fontdue::Font>], size: f32, line: &str, max_width: f32, force: bool) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let edges = char_edges(fonts, size, &chars);
+    let width = edges.last().map_or(0.0, |e| e.1);
+    if !force && width <= max_width {
+        return line.to_string();
+    }
+    // どのフォントにも「…」がなければ「...」
+    let ellipsis = if fonts.iter().any(|font| font.lookup_glyph_index('…') != 0) { "…" } else { "..." };
+    let ellipsis_width = char_edges(fonts, size, &ellipsis.chars().collect::<Vec<_>>()).last().map_or(0.0, |e| e.1);
+    let mut keep = chars.len();
+    while keep > 0 && (edges[keep - 1].1 + ellipsis_width > max_width || chars[keep - 1] == ' ') {
+        keep -= 1;
+    }
+    let mut result: String = chars[..keep].iter().collect();
+    result.push_str(ellipsis);
+    result
+}
@@ -0,0 +1,400 @@
+//! 入力イベントのキューとキー/マウス状態（Luaからは eguiモジュールの関数として利用）
+//! - egui.pollevents() でフレーム間に溜まったイベントをテーブルの配列で取得
+//! - egui.iskeydown(key), egui.mousepos(), egui.mousebuttons(), egui.modifiers() で現在の状態を取得
+//! - キー名は key_name() で列挙した固定の名前（egui.keynames() で一覧を取得できる）
+//! - keyhandler等のコールバックは従来通り呼ばれる（エラーはLuaErrorReportとして報告）
+
+use egui_winit::winit::event::{ElementState, Ime, ModifiersState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+use mlua::{IntoLuaMulti, Lua, Result as LuaResult, Table};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// pollevents されないまま溜まり続けないように古いイベントから捨てる
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+macro_rules! key_names {
+    ($($key:ident),* $(,)?) => {
+        // Luaに渡すキー名（winitのDebug表記に依存しないよう明示的に列挙する）
+        pub fn key_name(key: VirtualKeyCode) -> &'static str {
+            match key {
+                $(VirtualKeyCode::$key => stringify!($key),)*
+            }
+        }
+        pub const KEY_NAMES: &[&str] = &[$(stringify!($key)),*];
+    };
+}
+
+key_names!(
+    Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    F13, F14, F15, F16, F17, F18, F19, F20, F21, F22, F23, F24,
+    Snapshot, Scroll, Pause, Insert, Home, Delete, End, PageDown, PageUp,
+    Left, Up, Right, Down, Back, Return, Space, Compose, Caret, Numlock,
+    Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    NumpadAdd, NumpadDivide, NumpadDecimal, NumpadComma, NumpadEnter, NumpadEquals, NumpadMultiply, NumpadSubtract,
+    AbntC1, AbntC2, Apostrophe, Apps, Asterisk, At, Ax, Backslash, Calculator, Capital, Colon, Comma, Convert,
+    Equals, Grave, Kana, Kanji, LAlt, LBracket, LControl, LShift, LWin, Mail, MediaSelect, MediaStop, Minus,
+    Mute, MyComputer, NavigateForward, NavigateBackward, NextTrack, NoConvert, OEM102, Period, PlayPause, Plus,
+    Power, PrevTrack, RAlt, RBracket, RControl, RShift, RWin, Semicolon, Slash, Sleep, Stop, Sysrq, Tab,
+    Underline, Unlabeled, VolumeDown, VolumeUp, Wake, WebBack, WebFavorites, WebForward, WebHome, WebRefresh,
+    WebSearch, WebStop, Yen, Copy, Paste, Cut,
+);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub logo: bool, // Windowsキー/Commandキー（Luaでは "super"）
+}
+
+impl From<ModifiersState> for Modifiers {
+    fn from(state: ModifiersState) -> Self {
+        Self { shift: state.shift(), ctrl: state.ctrl(), alt: state.alt(), logo: state.logo() }
+    }
+}
+
+impl Modifiers {
+    fn set_fields(&self, table: &Table) -> LuaResult<()> {
+        table.set("shift", self.shift)?;
+        table.set("ctrl", self.ctrl)?;
+        table.set("alt", self.alt)?;
+        table.set("super", self.logo)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum InputEvent {
+    Key { pressed: bool, repeat: bool, key: Option<&'static str>, scancode: u32, modifiers: Modifiers },
+    Text { text: String },
+    Ime { kind: &'static str, text: String, cursor: Option<(usize, usize)> },
+    MouseButton { pressed: bool, button: String },
+    CursorMoved { x: f64, y: f64 },
+    CursorEntered { entered: bool },
+    Wheel { dx: f64, dy: f64, unit: &'static str },
+    FileDropped { path: String },
+    FileHovered { path: String },
+    FileHoverCancelled,
+    Focused { focused: bool },
+    Resized { width: u32, height: u32 },
+}
+
+impl InputEvent {
+    fn to_table<'lua>(&self, lua: &'lua Lua) -> LuaResult<Table<'lua>> {
+        let table = lua.create_table()?;
+        match self {
+            InputEvent::Key { pressed, repeat, key, scancode, modifiers } => {
+                table.set("type", "key")?;
+                table.set("state", state_name(*pressed))?;
+                table.set("repeated", *repeat)?;
+                table.set("key", *key)?;
+                table.set("scancode", *scancode)?;
+                modifiers.set_fields(&table)?;
+            }
+            InputEvent::Text { text } => {
+                table.set("type", "text")?;
+                table.set("text", text.as_str())?;
+            }
+            InputEvent::Ime { kind, text, cursor } => {
+                table.set("type", "ime")?;
+                table.set("kind", *kind)?;
+                table.set("text", text.as_str())?;
+                // カーソル位置はUTF-8のバイトオフセット（Luaの文字列インデックスは+1）
+                if let Some((start, end)) = cursor {
+                    table.set("cursorstart", *start)?;
+                    table.set("cursorend", *end)?;
+                }
+            }
+            InputEvent::MouseButton { pressed, button } => {
+                table.set("type", "mousebutton")?;
+                table.set("state", state_name(*pressed))?;
+                table.set("button", button.as_str())?;
+            }
+            InputEvent::CursorMoved { x, y } => {
+                table.set("type", "cursor")?;
+                table.set("x", *x)?;
+                table.set("y", *y)?;
+            }
+            InputEvent::CursorEntered { entered } => {
+                table.set("type", if *entered { "cursorenter" } else { "cursorleave" })?;
+            }
+            InputEvent::Wheel { dx, dy, unit } => {
+                table.set("type", "wheel")?;
+                table.set("dx", *dx)?;
+                table.set("dy", *dy)?;
+                table.set("unit", *unit)?;
+            }
+            InputEvent::FileDropped { path } => {
+                table.set("type", "filedrop")?;
+                table.set("path", path.as_str())?;
+            }
+            InputEvent::FileHovered { path } => {
+                table.set("type", "filehover")?;
+                table.set("path", path.as_str())?;
+            }
+            InputEvent::FileHoverCancelled => {
+                table.set("type", "filehovercancel")?;
+            }
+            InputEvent::Focused { focused } => {
+                table.set("type", "focus")?;
+                table.set("focused", *focused)?;
+            }
+            InputEvent::Resized { width, height } => {
+                table.set("type", "resize")?;
+                table.set("width", *width)?;
+                table.set("height", *height)?;
+            }
+        }
+        Ok(table)
+    }
+}
+
+fn state_name(pressed: bool) -> &'static str {
+    if pressed { "pressed" } else { "released" }
+}
+
+// ホイールの移動量 (dx, dy, 単位)。行単位(マウス)とピクセル単位(タッチパッド)がある
+pub fn wheel_delta(delta: &MouseScrollDelta) -> (f64, f64, &'static str) {
+    match delta {
+        MouseScrollDelta::LineDelta(x, y) => (*x as f64, *y as f64, "line"),
+        MouseScrollDelta::PixelDelta(pos) => (pos.x, pos.y, "pixel"),
+    }
+}
+
+pub fn mouse_button_name(button: &MouseButton) -> String {
+    match button {
+        MouseButton::Left => "left".to_string(),
+        MouseButton::Right => "right".to_string(),
+        MouseButton::Middle => "middle".to_string(),
+        MouseButton::Other(n) => format!("button{}", n),
+    }
+}
+
+pub struct InputState {
+    events: VecDeque<InputEvent>,
+    keys_down: HashSet<&'static str>,
+    mouse_buttons: HashSet<String>,
+    mouse_pos: (f64, f64),
+    modifiers: Modifiers,
+    ime_allowed_request: Option<bool>,
+    ime_position_request: Option<(f64, f64)>,
+}
+
+impl InputState {
+    pub fn new() -> Self {
+        Self {
+            events: VecDeque::new(),
+            keys_down: HashSet::new(),
+            mouse_buttons: HashSet::new(),
+            mouse_pos: (0.0, 0.0),
+            modifiers: Modifiers::default(),
+            ime_allowed_request: None,
+            ime_position_request: None,
+        }
+    }
+    fn push(&mut self, event: InputEvent) {
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+    // winitのイベントで状態を更新し、キューに積む
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => {
+                let pressed = input.state == ElementState::Pressed;
+                let key = input.virtual_keycode.map(key_name);
+                // 押されたままのキーに再度Pressedが来たらキーリピート
+                let mut repeat = false;
+                if let Some(key) = key {
+                    if pressed {
+                        repeat = !self.keys_down.insert(key);
+                    } else {
+                        self.keys_down.remove(key);
+                    }
+                }
+                let modifiers = self.modifiers;
+                self.push(InputEvent::Key { pressed, repeat, key, scancode: input.scancode, modifiers });
+            }
+            WindowEvent::ModifiersChanged(state) => {
+                self.modifiers = (*state).into();
+            }
+            // 制御文字（Backspace, Enter等）はkeyイベントで扱う
+            WindowEvent::ReceivedCharacter(ch) if !ch.is_control() => {
+                self.push(InputEvent::Text { text: ch.to_string() });
+            }
+            WindowEvent::Ime(ime) => {
+                let event = match ime {
+                    Ime::Enabled => InputEvent::Ime { kind: "enabled", text: String::new(), cursor: None },
+                    Ime::Preedit(text, cursor) => InputEvent::Ime { kind: "preedit", text: text.clone(), cursor: *cursor },
+                    Ime::Commit(text) => InputEvent::Ime { kind: "commit", text: text.clone(), cursor: None },
+                    Ime::Disabled => InputEvent::Ime { kind: "disabled", text: String::new(), cursor: None },
+                };
+                self.push(event);
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                let pressed = *state == ElementState::Pressed;
+                let button = mouse_button_name(button);
+                if pressed {
+                    self.mouse_buttons.insert(button.clone());
+                } else {
+                    self.mouse_buttons.remove(&button);
+                }
+                self.push(InputEvent::MouseButton { pressed, button });
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.mouse_pos = (position.x, position.y);
+                self.push(InputEvent::CursorMoved { x: position.x, y: position.y });
+            }
+            WindowEvent::CursorEntered { .. } => {
+                self.push(InputEvent::CursorEntered { entered: true });
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.push(InputEvent::CursorEntered { entered: false });
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let (dx, dy, unit) = wheel_delta(delta);
+                self.push(InputEvent::Wheel { dx, dy, unit });
+            }
+            WindowEvent::DroppedFile(path) => {
+                self.push(InputEvent::FileDropped { path: path.to_string_lossy().into_owned() });
+            }
+            WindowEvent::HoveredFile(path) => {
+                self.push(InputEvent::FileHovered { path: path.to_string_lossy().into_owned() });
+            }
+            WindowEvent::HoveredFileCancelled => {
+                self.push(InputEvent::FileHoverCancelled);
+            }
+            WindowEvent::Focused(focused) => {
+                if !focused {
+                    // フォーカスを失うとReleasedが届かないので押下状態を解除する
+                    self.keys_down.clear();
+                    self.mouse_buttons.clear();
+                    self.modifiers = Modifiers::default();
+                }
+                self.push(InputEvent::Focused { focused: *focused });
+            }
+            WindowEvent::Resized(size) => {
+                self.push(InputEvent::Resized { width: size.width, height: size.height });
+            }
+            _ => {}
+        }
+    }
+    pub fn is_key_down(&self, key: &str) -> bool {
+        self.keys_down.contains(key)
+    }
+    pub fn is_mouse_button_down(&self, button: &str) -> bool {
+        self.mouse_buttons.contains(button)
+    }
+    pub fn get_mouse_pos(&self) -> (f64, f64) {
+        self.mouse_pos
+    }
+    pub fn get_modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+    // egui.setime / egui.setimeposition による要求（main.rsでwinitのウィンドウに反映する）
+    pub fn take_ime_allowed_request(&mut self) -> Option<bool> {
+        self.ime_allowed_request.take()
+    }
+    pub fn take_ime_position_request(&mut self) -> Option<(f64, f64)> {
+        self.ime_position_request.take()
+    }
+    pub fn take_events(&mut self) -> Vec<InputEvent> {
+        self.events.drain(..).collect()
+    }
+    // スクリプトの再ロード時にキューを捨てる
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+}
+
+// イベントループから同期的に呼ぶ従来のハンドラ（egui.<name>）
+const HANDLER_NAMES: &[&str] = &[
+    "keyhandler",
+    "mousehandler",
+    "cursorhandler",
+    "cursorenterhandler",
+    "wheelhandler",
+    "filedrophandler",
+    "filehoverhandler",
+    "focushandler",
+    "resizehandler",
+];
+
+// egui.<name> に登録されたハンドラがあれば呼ぶ
+// エラーになったハンドラはイベントのたびに同じエラーを出さないように解除する
+pub fn call_handler<'lua, A: IntoLuaMulti<'lua>>(lua: &'lua Lua, name: &str, args: A) -> LuaResult<()> {
+    let egui_table: Table = lua.globals().get("egui")?;
+    match egui_table.get::<_, Option<mlua::Function>>(name)? {
+        Some(f) => f.call::<_, ()>(args).inspect_err(|_| {
+            let _ = egui_table.set(name, mlua::Value::Nil);
+        }),
+        None => Ok(()), // no handler registered
+    }
+}
+
+// スクリプトの再ロード時にハンドラと egui.onreload を外す（古いスクリプトのクロージャが呼ばれ続けないようにする）
+pub fn clear_handlers(lua: &Lua) -> LuaResult<()> {
+    let egui_table: Table = lua.globals().get("egui")?;
+    for name in HANDLER_NAMES {
+        egui_table.set(*name, mlua::Value::Nil)?;
+    }
+    egui_table.set("onreload", mlua::Value::Nil)?;
+    Ok(())
+}
+
+// egui.pollevents / egui.iskeydown / egui.mousepos / egui.mousebuttons / egui.modifiers
+// egui.keynames / egui.setime / egui.setimeposition を登録（register_eguiの後に呼ぶ）
+pub fn register_input(lua: &Lua, input: Arc<Mutex<InputState>>) -> LuaResult<()> {
+    let egui_table: Table = lua.globals().get("egui")?;
+    let i = input.clone();
+    egui_table.set("pollevents", lua.create_function(move |lua, ()| {
+        let events = i.lock().unwrap().take_events();
+        let table = lua.create_table_with_capacity(events.len(), 0)?;
+        for (n, event) in events.iter().enumerate() {
+            table.set(n + 1, event.to_table(lua)?)?;
+        }
+        Ok(table)
+    })?)?;
+    let i = input.clone();
+    egui_table.set("iskeydown", lua.create_function(move |_, key: String| {
+        Ok(i.lock().unwrap().is_key_down(&key))
+    })?)?;
+    let i = input.clone();
+    egui_table.set("mousepos", lua.create_function(move |_, ()| {
+        Ok(i.lock().unwrap().get_mouse_pos())
+    })?)?;
+    let i = input.clone();
+    egui_table.set("mousebuttons", lua.create_function(move |_, ()| {
+        let input = i.lock().unwrap();
+        Ok((
+            input.is_mouse_button_down("left"),
+            input.is_mouse_button_down("right"),
+            input.is_mouse_button_down("middle"),
+        ))
+    })?)?;
+    let i = input.clone();
+    egui_table.set("modifiers", lua.create_function(move |lua, ()| {
+        let table = lua.create_table()?;
+        i.lock().unwrap().get_modifiers().set_fields(&table)?;
+        Ok(table)
+    })?)?;
+    egui_table.set("keynames", lua.create_function(|_, ()| {
+        Ok(KEY_NAMES.to_vec())
+    })?)?;
+    // setime: IME入力の有効/無効（有効にすると type="ime" のイベントが届く）
+    let i = input.clone();
+    egui_table.set("setime", lua.create_function(move |_, allowed: bool| {
+        i.lock().unwrap().ime_allowed_request = Some(allowed);
+        Ok(())
+    })?)?;
+    // setimeposition: 変換ウィンドウの表示位置（親ウィンドウの物理座標）
+    let i = input.clone();
+    egui_table.set("setimeposition", lua.create_function(move |_, (x, y): (f64, f64)| {
+        i.lock().unwrap().ime_position_request = Some((x, y));
+        Ok(())
+    })?)?;
+    Ok(())
+}
@@ -1,16 +1,35 @@
 // luamod.rs
 // Lua VM・coroutine・API登録
-use mlua::{Lua, Result as LuaResult, StdLib, Thread, UserData, UserDataMethods, Variadic, LuaOptions};
+use mlua::{Lua, Result as LuaResult, StdLib, Thread, UserData, UserDataMethods, LuaOptions};
+use crate::luacanvas::{Canvas, CanvasState};
 use std::fs;
 use std::sync::{Arc, Mutex};
 
-// Luaグローバル関数 sleep(millisec) を登録
+// スレッドをブロックして待つ（math.hugeなど表現できない時間は戻らない）
+pub fn blocking_sleep(secs: f64) {
+    match std::time::Duration::try_from_secs_f64(secs.max(0.0)) {
+        Ok(duration) => std::thread::sleep(duration),
+        Err(_) => loop {
+            std::thread::park();
+        },
+    }
+}
+
+// Luaグローバル関数 sleep(secs) / blockingsleep(secs) を登録
+// sleepはメインスクリプトとタスクの中ではイベントループへ処理を戻しながら待機する（mluaのasync関数）
+// REPLやイベントハンドラ、スクリプトが自分で作ったcoroutineの中では従来通りスレッドをブロックする
 pub fn register_sleep(lua: &Lua) -> LuaResult<()> {
-    let sleep_fn = lua.create_function(|_, secs: f64| {
-        std::thread::sleep(std::time::Duration::from_secs_f64(secs));
+    let blocking_sleep_fn = lua.create_function(|_, secs: f64| {
+        blocking_sleep(secs);
+        Ok(())
+    })?;
+    let async_sleep_fn = lua.create_async_function(|_, secs: f64| async move {
+        crate::luatask::Deadline::after(secs).await;
         Ok(())
     })?;
+    let sleep_fn = crate::luatask::async_or_fallback(lua, "sleep", async_sleep_fn, blocking_sleep_fn.clone())?;
     lua.globals().set("sleep", sleep_fn)?;
+    lua.globals().set("blockingsleep", blocking_sleep_fn)?;
     Ok(())
 }
 
@@ -60,510 +79,242 @@ pub fn register_localdatetime(_lua: &Lua) -> LuaResult<()> {
     Ok(())
 }
 
+// キャンバス上のポインタイベント（座標はキャンバスのピクセル単位）
+#[derive(Clone, Debug)]
+pub enum PointerEvent {
+    Click { x: f32, y: f32, button: &'static str, double: bool },
+    Drag { x: f32, y: f32, dx: f32, dy: f32, button: &'static str, phase: &'static str },
+}
+
 pub struct LuaWindow {
-    pub id: String,
+    pub id: String, // egui WindowのIdとテクスチャのキー（作成後は変更しない）
+    pub title: String,
+    pub visible: bool,
+    pub closed: bool, // trueならフレームの終わりにwindowsから取り除かれる
+    pub kept: bool, // 再ロード時に残されたウィンドウ（新しいスクリプトの同名・同サイズのcreate_windowで再利用）
+    pub close_requested: bool, // egui Windowの×ボタンが押された（onclose呼び出し待ち）
+    pub pending_pos: Option<(i32, i32)>, // setposで指定された位置（次の描画で反映）
+    pub scale: f32, // 表示倍率（キャンバス1ピクセルをscale x scaleポイントで表示）
+    pub filter: egui::TextureOptions, // 拡大縮小時のテクスチャフィルタ
+    pub fit: bool, // trueならegui Windowの大きさに合わせて縦横比を保って拡大縮小
     pub x: i32, // 親ウィンドウ座標
     pub y: i32, // 親ウィンドウ座標
     pub width: usize,
     pub height: usize,
     pub buffer: Vec<u8>, // RGBA * (width*height)
-    pub text_color: (u8, u8, u8, u8),
-    pub text_font_size: usize,
-    pub fillpaint_stack: Vec<(i32, i32)>,
-    pub fillpaint_visited: Vec<bool>,
+    pub canvas: CanvasState, // 文字色・フォントなど描画の設定
+    pub pointer_pos: Option<(f32, f32)>, // キャンバス座標でのポインタ位置（キャンバス外の場合もある）
+    pub hovered: bool,
+    pub pointer_events: Vec<PointerEvent>,
+    pub onclick: Option<mlua::RegistryKey>,
+    pub ondrag: Option<mlua::RegistryKey>,
+    pub onclose: Option<mlua::RegistryKey>,
+    pub onui: Option<mlua::RegistryKey>, // eguiウィジェットを配置する関数（描画時に呼ばれる）
+    pub ui_side: crate::luaui::UiSide,
+    pub dirty: Option<(usize, usize, usize, usize)>, // 前回のテクスチャ転送以降に描画された範囲 (x0, y0, x1, y1)、x1/y1は含まない
 }
 
 impl LuaWindow {
-    #[inline(always)]
-    // 境界チェックなし、高速化、アルファブレンドあり
-    pub fn unsafe_point(&mut self, x: i32, y: i32, r: u8, g: u8, b: u8, a: u8) {
-        // TODO: point以外でも使えそう（ただしtextは text_color+フォントのアルファの影響を受けるので別実装）
-        let idx = (y as usize * self.width + x as usize) * 4;
-
-        let dst_r = self.buffer[idx] as i32;
-        let dst_g = self.buffer[idx + 1] as i32;
-        let dst_b = self.buffer[idx + 2] as i32;
-        let dst_a = self.buffer[idx + 3] as i32;
-
-        let src_r = r as i32;
-        let src_g = g as i32;
-        let src_b = b as i32;
-        let src_a = a as i32;
-
-        // アルファブレンド (整数演算)
-        // out_a = src_a + dst_a * (255 - src_a) / 255
-        let out_a = src_a + ((dst_a * (255 - src_a)) / 255);
-        if out_a > 0 {
-            self.buffer[idx] = ((src_r * src_a + dst_r * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-            self.buffer[idx + 1] = ((src_g * src_a + dst_g * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-            self.buffer[idx + 2] = ((src_b * src_a + dst_b * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-            self.buffer[idx + 3] = out_a.min(255) as u8;
+    pub fn new(id: &str, width: usize, height: usize) -> Self {
+        Self {
+            id: id.to_string(),
+            title: id.to_string(),
+            visible: true,
+            closed: false,
+            kept: false,
+            close_requested: false,
+            pending_pos: None,
+            scale: 1.0,
+            filter: egui::TextureOptions::NEAREST,
+            fit: false,
+            x: 0,
+            y: 0,
+            width,
+            height,
+            buffer: vec![0; width * height * 4],
+            canvas: CanvasState::new(false),
+            pointer_pos: None,
+            hovered: false,
+            pointer_events: Vec::new(),
+            onclick: None,
+            ondrag: None,
+            onclose: None,
+            onui: None,
+            ui_side: crate::luaui::UiSide::Bottom,
+            dirty: Some((0, 0, width, height)),
+        }
+    }
+
+    // 再ロードで再利用するときに、前のスクリプトのコールバックと描画の設定を捨てる（内容は残す）
+    pub fn reset_for_reload(&mut self) {
+        self.kept = false;
+        self.onclick = None;
+        self.ondrag = None;
+        self.onclose = None;
+        self.onui = None;
+        self.ui_side = crate::luaui::UiSide::Bottom;
+        self.pointer_events.clear();
+        self.close_requested = false;
+        self.canvas = CanvasState::new(false);
+    }
+
+    // 変更範囲を取り出す（テクスチャ転送時）
+    pub fn take_dirty(&mut self) -> Option<(usize, usize, usize, usize)> {
+        self.dirty.take()
+    }
+    // 指定範囲のRGBAを切り出す（部分的なテクスチャ転送用）
+    pub fn region_rgba(&self, x0: usize, y0: usize, x1: usize, y1: usize) -> Vec<u8> {
+        let mut region = Vec::with_capacity((x1 - x0) * (y1 - y0) * 4);
+        for y in y0..y1 {
+            let start = (y * self.width + x0) * 4;
+            region.extend_from_slice(&self.buffer[start..start + (x1 - x0) * 4]);
+        }
+        region
+    }
+
+    // 表示サイズ（ポイント）。fitの場合は available に収まる最大サイズ
+    pub fn display_size(&self, available: egui::Vec2) -> egui::Vec2 {
+        let size = egui::Vec2::new(self.width as f32, self.height as f32);
+        if self.fit && available.x > 0.0 && available.y > 0.0 {
+            size * (available.x / size.x).min(available.y / size.y)
         } else {
-            self.buffer[idx] = 0;
-            self.buffer[idx + 1] = 0;
-            self.buffer[idx + 2] = 0;
-            self.buffer[idx + 3] = 0;
+            size * self.scale
         }
     }
-    
-    pub fn point(&mut self, x: i32, y: i32, r: u8, g: u8, b: u8, a: u8) {
-        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
-            return;
+
+    // create_window の第4引数 / w:setoptions のテーブル {scale=n, filter="nearest"|"linear", fit=bool}
+    pub fn apply_options(&mut self, options: &mlua::Table) -> LuaResult<()> {
+        if let Some(scale) = options.get::<_, Option<f32>>("scale")? {
+            self.set_scale(scale)?;
+        }
+        if let Some(filter) = options.get::<_, Option<String>>("filter")? {
+            self.set_filter(&filter)?;
         }
-        self.unsafe_point(x, y, r, g, b, a);
+        if let Some(fit) = options.get::<_, Option<bool>>("fit")? {
+            self.fit = fit;
+        }
+        Ok(())
     }
-    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, r: u8, g: u8, b: u8, a: u8) {
-        let mut x0 = x0;
-        let mut y0 = y0;
-        let dx = (x1 - x0).abs();
-        let sx = if x0 < x1 { 1 } else { -1 };
-        let dy = -(y1 - y0).abs();
-        let sy = if y0 < y1 { 1 } else { -1 };
-        let mut err = dx + dy;
-        loop {
-            self.point(x0, y0, r, g, b, a);
-            if x0 == x1 && y0 == y1 {
-                break;
-            }
-            let e2 = 2 * err;
-            if e2 >= dy {
-                err += dy;
-                x0 += sx;
-            }
-            if e2 <= dx {
-                err += dx;
-                y0 += sy;
-            }
+    fn set_scale(&mut self, scale: f32) -> LuaResult<()> {
+        if scale.is_nan() || scale <= 0.0 {
+            return Err(mlua::Error::external("scale must be positive"));
         }
+        self.scale = scale;
+        Ok(())
     }
-    pub fn circle(&mut self, cx: i32, cy: i32, radius: i32, r: u8, g: u8, b: u8, a: u8) {
-        let mut x = radius;
-        let mut y = 0;
-        let mut q = radius;
-        while x >= y {
-            let points = [
-                (cx + x, cy + y),
-                (cx - x, cy + y),
-                (cx + x, cy - y),
-                (cx - x, cy - y),
-                (cx + y, cy + x),
-                (cx - y, cy + x),
-                (cx + y, cy - x),
-                (cx - y, cy - x),
-            ];
-            for &(px, py) in &points {
-                self.point(px, py, r, g, b, a);
-            }
-            q = q - y - y - 1;
-            y = y + 1;
-            if q < 0 {
-                q = q + x + x - 1;
-                x = x - 1;
+    fn set_filter(&mut self, filter: &str) -> LuaResult<()> {
+        self.filter = match filter {
+            "nearest" => egui::TextureOptions::NEAREST,
+            "linear" => egui::TextureOptions::LINEAR,
+            _ => return Err(mlua::Error::external(format!("unknown filter: {} (nearest or linear)", filter))),
+        };
+        // フィルタは全体の転送時に反映される
+        self.mark_all_dirty();
+        Ok(())
+    }
+
+    // キャンバスサイズを変更する（重なる部分の内容は残し、広がった部分は透明）
+    pub fn resize(&mut self, width: usize, height: usize) {
+        let mut buffer = vec![0u8; width * height * 4];
+        let copy_w = width.min(self.width) * 4;
+        for y in 0..height.min(self.height) {
+            let src = y * self.width * 4;
+            let dst = y * width * 4;
+            buffer[dst..dst + copy_w].copy_from_slice(&self.buffer[src..src + copy_w]);
+        }
+        self.buffer = buffer;
+        self.width = width;
+        self.height = height;
+        self.canvas.fillpaint_stack.clear();
+        self.canvas.fillpaint_visited.clear();
+        self.pointer_pos = None;
+        self.hovered = false;
+        self.mark_all_dirty();
+    }
+
+    // 描画したキャンバス画像のResponseからポインタ状態・イベントを更新する
+    // egui座標(ポイント)を画像の表示領域に対するキャンバスのピクセル座標へ変換するので pixels_per_point や拡大表示の影響を受けない
+    pub fn update_pointer(&mut self, response: &egui::Response) {
+        let rect = response.rect;
+        if rect.width() <= 0.0 || rect.height() <= 0.0 {
+            return;
+        }
+        let scale_x = self.width as f32 / rect.width();
+        let scale_y = self.height as f32 / rect.height();
+        let to_canvas = |p: egui::Pos2| ((p.x - rect.min.x) * scale_x, (p.y - rect.min.y) * scale_y);
+        self.hovered = response.hovered();
+        self.pointer_pos = response.ctx.pointer_latest_pos().map(to_canvas);
+        let buttons = [
+            (egui::PointerButton::Primary, "left"),
+            (egui::PointerButton::Secondary, "right"),
+            (egui::PointerButton::Middle, "middle"),
+        ];
+        let Some((x, y)) = response.interact_pointer_pos().or(response.hover_pos()).map(to_canvas) else {
+            return;
+        };
+        for (button, name) in buttons {
+            if response.clicked_by(button) || response.double_clicked_by(button) {
+                let double = response.double_clicked_by(button);
+                self.pointer_events.push(PointerEvent::Click { x, y, button: name, double });
             }
+            let phase = if response.drag_started_by(button) {
+                "start"
+            } else if response.drag_released_by(button) {
+                "end"
+            } else if response.dragged_by(button) {
+                "drag"
+            } else {
+                continue;
+            };
+            let delta = response.drag_delta();
+            self.pointer_events.push(PointerEvent::Drag { x, y, dx: delta.x * scale_x, dy: delta.y * scale_y, button: name, phase });
         }
     }
-    pub fn set_text_color(&mut self, r: u8, g: u8, b: u8, a: u8) {
-        self.text_color = (r, g, b, a);
+}
+
+impl Canvas for LuaWindow {
+    fn width(&self) -> usize {
+        self.width
     }
-    pub fn get_text_color(&self) -> (u8, u8, u8, u8) {
-        self.text_color
+    fn height(&self) -> usize {
+        self.height
     }
-    pub fn set_text_font_size(&mut self, size: usize) {
-        self.text_font_size = size;
+    fn pixels(&self) -> &[u8] {
+        &self.buffer
     }
-    pub fn get_text_font_size(&self) -> usize {
-        self.text_font_size
+    fn pixels_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
     }
-    pub fn text(&mut self, x: i32, y: i32, text: &str) -> (usize, usize) {
-        use std::sync::OnceLock;
-        use unicode_width::UnicodeWidthChar;
-        static FONT: OnceLock<fontdue::Font> = OnceLock::new();
-        let font = FONT.get_or_init(|| {
-            let data = std::fs::read("assets/fonts.ttf").expect("assets/fonts.ttf not found");
-            fontdue::Font::from_bytes(data, fontdue::FontSettings::default())
-                .expect("font load failed")
-        });
-        let (r, g, b, a) = self.text_color;
-        let font_size = self.text_font_size as f32;
-        let mut pen_x = x;
-        let base_y = y + self.text_font_size as i32;
-        let mut width = 0;
-        for ch in text.chars() {
-            let (metrics, bitmap) = font.rasterize(ch, font_size);
-            // 下ぞろえ: ベースラインから高さ分引く
-            let draw_y = base_y - metrics.height as i32 - metrics.ymin;
-            for dy in 0..metrics.height {
-                for dx in 0..metrics.width {
-                    let cov = bitmap[dy * metrics.width + dx];
-                    if cov > 0 {
-                        let px = pen_x + dx as i32 + metrics.xmin;
-                        let py = draw_y + dy as i32;
-                        if px >= 0 && px < self.width as i32 && py >= 0 && py < self.height as i32 {
-                            let idx = (py as usize * self.width + px as usize) * 4;
-                            let src_a = (cov as i32 * a as i32) / 255;
-                            let dst_r = self.buffer[idx] as i32;
-                            let dst_g = self.buffer[idx + 1] as i32;
-                            let dst_b = self.buffer[idx + 2] as i32;
-                            let dst_a = self.buffer[idx + 3] as i32;
-                            let out_a = src_a + ((dst_a * (255 - src_a)) / 255);
-                            if out_a > 0 {
-                                self.buffer[idx] = ((r as i32 * src_a + dst_r * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-                                self.buffer[idx + 1] = ((g as i32 * src_a + dst_g * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-                                self.buffer[idx + 2] = ((b as i32 * src_a + dst_b * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-                                self.buffer[idx + 3] = out_a.min(255) as u8;
-                            } else {
-                                self.buffer[idx] = 0;
-                                self.buffer[idx + 1] = 0;
-                                self.buffer[idx + 2] = 0;
-                                self.buffer[idx + 3] = 0;
-                            }
-                        }
-                    }
-                }
-            }
-            // 半角/全角で横幅を調整
-            let ch_width = match UnicodeWidthChar::width(ch) {
-                Some(1) => (self.text_font_size / 2) as i32,
-                _ => self.text_font_size as i32,
-            };
-            pen_x += ch_width;
-            width += ch_width as usize;
-        }
-        (width, self.text_font_size)
-    }
-    pub fn scroll(&mut self, dx: i32, dy: i32, r: u8, g: u8, b: u8, a: u8) {
-        let (w, h) = (self.width as i32, self.height as i32);
-        let mut new_buf = vec![0u8; self.buffer.len()];
-        for y in 0..h {
-            for x in 0..w {
-                let nx = x - dx;
-                let ny = y - dy;
-                let idx = (y as usize * self.width + x as usize) * 4;
-                if nx >= 0 && nx < w && ny >= 0 && ny < h {
-                    let src = (ny as usize * self.width + nx as usize) * 4;
-                    new_buf[idx..idx + 4].copy_from_slice(&self.buffer[src..src + 4]);
-                } else {
-                    new_buf[idx] = r;
-                    new_buf[idx + 1] = g;
-                    new_buf[idx + 2] = b;
-                    new_buf[idx + 3] = a;
-                }
-            }
+    fn state(&self) -> &CanvasState {
+        &self.canvas
+    }
+    fn state_mut(&mut self) -> &mut CanvasState {
+        &mut self.canvas
+    }
+    // 描画した範囲を記録する（キャンバス外はクリップ、x1/y1は含まない）
+    fn mark_dirty(&mut self, x0: i32, y0: i32, x1: i32, y1: i32) {
+        let x0 = x0.clamp(0, self.width as i32) as usize;
+        let y0 = y0.clamp(0, self.height as i32) as usize;
+        let x1 = x1.clamp(0, self.width as i32) as usize;
+        let y1 = y1.clamp(0, self.height as i32) as usize;
+        if x0 >= x1 || y0 >= y1 {
+            return;
         }
-        self.buffer = new_buf;
+        self.dirty = Some(match self.dirty {
+            Some((dx0, dy0, dx1, dy1)) => (dx0.min(x0), dy0.min(y0), dx1.max(x1), dy1.max(y1)),
+            None => (x0, y0, x1, y1),
+        });
+    }
+    fn mark_all_dirty(&mut self) {
+        self.dirty = Some((0, 0, self.width, self.height));
     }
 }
 
 impl UserData for LuaWindow {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        // #region image methods
-        // drawimage: w:drawimage(img, x, y, img_sx, img_sy, img_dx, img_dy)
-        methods.add_method_mut(
-            "drawimage",
-            |_, this, (img, x, y, img_sx, img_sy, img_dx, img_dy): (mlua::AnyUserData, i32, i32, Option<u32>, Option<u32>, Option<u32>, Option<u32>)| {
-                use crate::luaimage::LuaImage;
-                let img = img.borrow::<LuaImage>()?;
-                let sx = img_sx.unwrap_or(0);
-                let sy = img_sy.unwrap_or(0);
-                let dx = img_dx.unwrap_or(img.img.width());
-                let dy = img_dy.unwrap_or(img.img.height());
-                // 切り取り
-                let subimg = img.img.crop_imm(sx, sy, dx, dy);
-                let (w, h) = (this.width as i32, this.height as i32);
-                let subimg = subimg.to_rgba8();
-                for iy in 0..subimg.height() {
-                    for ix in 0..subimg.width() {
-                        let px = x + ix as i32;
-                        let py = y + iy as i32;
-                        if px >= 0 && py >= 0 && px < w && py < h {
-                            // this.point(px, py, rgba[0], rgba[1], rgba[2], rgba[3]);
-                            let idx = (py as usize * this.width + px as usize) * 4;
-                            let rgba = subimg.get_pixel(ix, iy).0;
-                            let src_r = rgba[0] as i32;
-                            let src_g = rgba[1] as i32;
-                            let src_b = rgba[2] as i32;
-                            let src_a = rgba[3] as i32;
-                            let dst_r = this.buffer[idx] as i32;
-                            let dst_g = this.buffer[idx + 1] as i32;
-                            let dst_b = this.buffer[idx + 2] as i32;
-                            let dst_a = this.buffer[idx + 3] as i32;
-
-                            // out_a = src_a + dst_a * (255 - src_a) / 255
-                            let out_a = src_a + ((dst_a * (255 - src_a)) / 255);
-                            if out_a > 0 {
-                                this.buffer[idx] = ((src_r * src_a + dst_r * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-                                this.buffer[idx + 1] = ((src_g * src_a + dst_g * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-                                this.buffer[idx + 2] = ((src_b * src_a + dst_b * dst_a * (255 - src_a) / 255) / out_a).min(255) as u8;
-                                this.buffer[idx + 3] = out_a.min(255) as u8;
-                            } else {
-                                this.buffer[idx] = 0;
-                                this.buffer[idx + 1] = 0;
-                                this.buffer[idx + 2] = 0;
-                                this.buffer[idx + 3] = 0;
-                            }
-                        }
-                    }
-                }
-                Ok(())
-            }
-        );
-        // captureimage: w:captureimage(x, y, width, height)
-        methods.add_method(
-            "captureimage",
-            |lua, this, (x, y, width, height): (i32, i32, u32, u32)| {
-                use crate::luaimage::LuaImage;
-                let mut buf = vec![0u8; (width * height * 4) as usize];
-                for iy in 0..height {
-                    for ix in 0..width {
-                        let sx = x + ix as i32;
-                        let sy = y + iy as i32;
-                        let idx_src = if sx >= 0 && sy >= 0 && sx < this.width as i32 && sy < this.height as i32 {
-                            (sy as usize * this.width + sx as usize) * 4
-                        } else {
-                            continue;
-                        };
-                        let idx_dst = (iy as usize * width as usize + ix as usize) * 4;
-                        buf[idx_dst] = this.buffer[idx_src];
-                        buf[idx_dst + 1] = this.buffer[idx_src + 1];
-                        buf[idx_dst + 2] = this.buffer[idx_src + 2];
-                        buf[idx_dst + 3] = this.buffer[idx_src + 3];
-                    }
-                }
-                let img = image::RgbaImage::from_vec(width, height, buf).unwrap();
-                let dynimg = image::DynamicImage::ImageRgba8(img);
-                let luaimg = LuaImage { img: dynimg };
-                let ud = lua.create_userdata(luaimg)?;
-                Ok(ud)
-            }
-        );
-        // #endregion image methods
-        // #region graphic methods
-        methods.add_method_mut(
-            "cls",
-            |_, this, (r, g, b, a): (Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let r = r.unwrap_or(0);
-                let g = g.unwrap_or(0);
-                let b = b.unwrap_or(0);
-                let a = a.unwrap_or(255);
-                for px in this.buffer.chunks_mut(4) {
-                    px[0] = r;
-                    px[1] = g;
-                    px[2] = b;
-                    px[3] = a;
-                }
-                Ok(())
-            },
-        );
-        methods.add_method_mut(
-            "point",
-            |_, this, (x, y, r, g, b, a): (i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let r = r.unwrap_or(255);
-                let g = g.unwrap_or(255);
-                let b = b.unwrap_or(255);
-                let a = a.unwrap_or(255);
-                this.point(x, y, r, g, b, a);
-                Ok(())
-            },
-        );
-        methods.add_method(
-            "getpoint",
-            |_, this, (x, y): (i32, i32)| {
-                if x < 0 || y < 0 || x >= this.width as i32 || y >= this.height as i32 {
-                    return Ok((0u8, 0u8, 0u8, 0u8));
-                }
-                let idx = (y as usize * this.width + x as usize) * 4;
-                let r = this.buffer[idx];
-                let g = this.buffer[idx + 1];
-                let b = this.buffer[idx + 2];
-                let a = this.buffer[idx + 3];
-                Ok((r, g, b, a))
-            },
-        );
-        methods.add_method(
-            "getpointi",
-            |lua, this, (x, y, table,): (i32, i32, Option<mlua::Table>,)| {
-                let table = match table {
-                    Some(t) => t,
-                    None => lua.create_table()?,
-                };
-                
-                if x < 0 || y < 0 || x >= this.width as i32 || y >= this.height as i32 {
-                    table.set(1, 0)?;
-                    table.set(2, 0)?;
-                    table.set(3, 0)?;
-                    table.set(4, 0)?;
-                } else {
-                    let idx = (y as usize * this.width + x as usize) * 4;
-                    let r = this.buffer[idx];
-                    let g = this.buffer[idx + 1];
-                    let b = this.buffer[idx + 2];
-                    let a = this.buffer[idx + 3];
-                    table.set(1, r)?;
-                    table.set(2, g)?;
-                    table.set(3, b)?;
-                    table.set(4, a)?;
-                }
-                Ok(table)
-            },
-        );
-        methods.add_method_mut(
-            "line",
-            |_, this, (x0, y0, x1, y1, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let r = r.unwrap_or(255);
-                let g = g.unwrap_or(255);
-                let b = b.unwrap_or(255);
-                let a = a.unwrap_or(255);
-                this.line(x0, y0, x1, y1, r, g, b, a);
-                Ok(())
-            },
-        );
-        methods.add_method_mut(
-            "circle",
-            |_, this, (cx, cy, radius, r, g, b, a): (i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let r = r.unwrap_or(255);
-                let g = g.unwrap_or(255);
-                let b = b.unwrap_or(255);
-                let a = a.unwrap_or(255);
-                this.circle(cx, cy, radius, r, g, b, a);
-                Ok(())
-            },
-        );
-        methods.add_method_mut(
-            "rect",
-            |_, this, (x1, y1, x2, y2, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let r = r.unwrap_or(255);
-                let g = g.unwrap_or(255);
-                let b = b.unwrap_or(255);
-                let a = a.unwrap_or(255);
-                this.line(x1, y1, x2, y1, r, g, b, a);
-                this.line(x2, y1, x2, y2, r, g, b, a);
-                this.line(x2, y2, x1, y2, r, g, b, a);
-                this.line(x1, y2, x1, y1, r, g, b, a);
-                Ok(())
-            },
-        );
-        methods.add_method_mut(
-            "fillrect",
-            |_, this, (x1, y1, x2, y2, r, g, b, a): (i32, i32, i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let r = r.unwrap_or(255);
-                let g = g.unwrap_or(255);
-                let b = b.unwrap_or(255);
-                let a = a.unwrap_or(255);
-                for y in y1.min(y2)..=y1.max(y2) {
-                    this.line(x1, y, x2, y, r, g, b, a);
-                }
-                Ok(())
-            },
-        );
-        methods.add_method_mut(
-            "scroll",
-            |_, this, (dx, dy, r, g, b, a): (i32, i32, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                this.scroll(dx, dy, r.unwrap_or(0), g.unwrap_or(0), b.unwrap_or(0), a.unwrap_or(255)); // alphaは255固定
-                Ok(())
-            },
-        );
-        methods.add_method_mut(
-            "paint",
-            |_, this, (x, y, r, g, b, a, sr, sg, sb, sa): (i32, i32, u8, u8, u8, Option<u8>, Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-                let a = a.unwrap_or(255);
-                let sr = sr.unwrap_or(r);
-                let sg = sg.unwrap_or(g);
-                let sb = sb.unwrap_or(b);
-                let sa = sa.unwrap_or(a);
-                let (w, h) = (this.width as i32, this.height as i32);
-                if x < 0 || y < 0 || x >= w || y >= h {
-                    return Ok(0);
-                }
-                this.fillpaint_stack.clear();
-                this.fillpaint_visited.fill(false);
-                // let mut stack: Vec<(i32, i32)> = Vec::with_capacity((w * h).min(4096) as usize);
-                // let mut visited = vec![false; (w * h) as usize];
-
-                let boundary = (sr, sg, sb, sa);
-                let fill = (r, g, b, a);
-                let mut count: usize = 0;
-
-                // 既に塗りつぶし色なら何もしない
-                let idx0 = (y as usize * this.width + x as usize) * 4;
-                let pixel0 = (
-                    this.buffer[idx0],
-                    this.buffer[idx0 + 1],
-                    this.buffer[idx0 + 2],
-                    this.buffer[idx0 + 3],
-                );
-                if pixel0 == boundary {
-                    return Ok(0);
-                }
+        // 描画メソッド（graphicバッファと共通）
+        crate::luacanvas::add_canvas_methods(methods);
 
-                this.fillpaint_stack.push((x, y));
-                while let Some((cx, cy)) = this.fillpaint_stack.pop() {
-                    if cx < 0 || cy < 0 || cx >= w || cy >= h {
-                        continue;
-                    }
-                    let idx = cy as usize * this.width + cx as usize;
-                    if this.fillpaint_visited[idx] {
-                        continue;
-                    }
-                    let idx_buf = idx * 4;
-                    let pr = this.buffer[idx_buf];
-                    let pg = this.buffer[idx_buf + 1];
-                    let pb = this.buffer[idx_buf + 2];
-                    let pa = this.buffer[idx_buf + 3];
-                    if (pr, pg, pb, pa) == boundary || (pr, pg, pb, pa) == fill {
-                        continue;
-                    }
-                    this.unsafe_point(cx, cy, r, g, b, a);
-                    this.fillpaint_visited[idx] = true;
-                    this.fillpaint_stack.push((cx + 1, cy));
-                    this.fillpaint_stack.push((cx - 1, cy));
-                    this.fillpaint_stack.push((cx, cy + 1));
-                    this.fillpaint_stack.push((cx, cy - 1));
-                    count += 1;
-                }
-                Ok(count)
-            },
-        );
-        // #endregion graphic methods
-        // #region text methods
-        methods.add_method_mut("settextcolor", |_, this, (r, g, b, a): (Option<u8>, Option<u8>, Option<u8>, Option<u8>)| {
-            this.set_text_color(r.unwrap_or(255), g.unwrap_or(255), b.unwrap_or(255), a.unwrap_or(255));
-            // alphaはset_text_colorで255固定
-            Ok(())
-        });
-        methods.add_method("gettextcolor", |_, this, ()| {
-            let (r, g, b, a) = this.get_text_color();
-            Ok((r, g, b, a))
-        });
-        methods.add_method_mut(
-            "settextfontsize",
-            |_, this, size: usize| {
-                this.set_text_font_size(size);
-                Ok(())
-            },
-        );
-        methods.add_method("gettextfontsize", |_, this, ()| {
-            let size = this.get_text_font_size();
-            Ok(size)
-        });
-        methods.add_method_mut(
-            "text",
-            |_, this, (x, y, args): (i32, i32, Variadic<mlua::Value>)| {
-                let s = args
-                .iter()
-                    .map(|v| match v {
-                        mlua::Value::String(s) => s.to_str().unwrap_or("").to_owned(),
-                        mlua::Value::Integer(i) => i.to_string(),
-                        mlua::Value::Number(f) => f.to_string(),
-                        mlua::Value::Boolean(b) => b.to_string(),
-                        _ => "".to_owned(),
-                    })
-                    .collect::<Vec<_>>()
-                    .join(" ");
-                Ok(this.text(x, y, &s))
-            },
-        );
-        // #endregion text methods
-        
         // #region metric methods
         methods.add_method("getx", |_, this, ()| {
             Ok(this.x)
@@ -571,16 +322,210 @@ impl UserData for LuaWindow {
         methods.add_method("gety", |_, this, ()| {
             Ok(this.y)
         });
-        methods.add_method("getwidth", |_, this, ()| {
-            Ok(this.width)
+        // #endregion metric methods
+
+        // #region window methods
+        // close: ウィンドウを閉じる（以降の描画は可能だが表示されない）
+        methods.add_method_mut("close", |_, this, ()| {
+            this.closed = true;
+            Ok(())
         });
-        methods.add_method("getheight", |_, this, ()| {
-            Ok(this.height)
+        methods.add_method("isclosed", |_, this, ()| {
+            Ok(this.closed)
         });
-        // #endregion metric methods
+        methods.add_method_mut("settitle", |_, this, title: String| {
+            this.title = title;
+            Ok(())
+        });
+        methods.add_method("gettitle", |_, this, ()| {
+            Ok(this.title.clone())
+        });
+        // resize: w:resize(width, height) キャンバスを作り直す（左上を基準に内容を残す）
+        methods.add_method_mut("resize", |_, this, (width, height): (usize, usize)| {
+            if width == 0 || height == 0 {
+                return Err(mlua::Error::external("resize: width and height must be positive"));
+            }
+            this.resize(width, height);
+            Ok(())
+        });
+        methods.add_method_mut("setvisible", |_, this, visible: bool| {
+            this.visible = visible;
+            if !visible {
+                this.hovered = false;
+            }
+            Ok(())
+        });
+        methods.add_method("isvisible", |_, this, ()| {
+            Ok(this.visible)
+        });
+        // setpos: w:setpos(x, y) 親ウィンドウ座標でのウィンドウ位置
+        methods.add_method_mut("setpos", |_, this, (x, y): (i32, i32)| {
+            this.pending_pos = Some((x, y));
+            this.x = x;
+            this.y = y;
+            Ok(())
+        });
+        // setscale: w:setscale(scale) 表示倍率（描画座標はキャンバスのピクセルのまま）
+        methods.add_method_mut("setscale", |_, this, scale: f32| {
+            this.set_scale(scale)
+        });
+        methods.add_method("getscale", |_, this, ()| {
+            Ok(this.scale)
+        });
+        // setfilter: w:setfilter("nearest"|"linear")
+        methods.add_method_mut("setfilter", |_, this, filter: String| {
+            this.set_filter(&filter)
+        });
+        methods.add_method("getfilter", |_, this, ()| {
+            Ok(if this.filter == egui::TextureOptions::LINEAR { "linear" } else { "nearest" })
+        });
+        // setfit: w:setfit(true) egui Windowのサイズに合わせて表示（縦横比は保つ）
+        methods.add_method_mut("setfit", |_, this, fit: bool| {
+            this.fit = fit;
+            Ok(())
+        });
+        methods.add_method_mut("setoptions", |_, this, options: mlua::Table| {
+            this.apply_options(&options)
+        });
+        // onclose: w:onclose(function(w) end) ×ボタンで閉じられるときに呼ばれる、falseを返すと閉じない
+        methods.add_method_mut("onclose", |lua, this, f: Option<mlua::Function>| {
+            this.onclose = f.map(|f| lua.create_registry_value(f)).transpose()?;
+            Ok(())
+        });
+        // onui: w:onui(function(ui) end[, side]) キャンバスの横にeguiウィジェットを表示、sideは "top"/"bottom"(デフォルト)/"left"/"right"
+        methods.add_method_mut("onui", |lua, this, (f, side): (Option<mlua::Function>, Option<String>)| {
+            if let Some(side) = side {
+                this.ui_side = crate::luaui::UiSide::from_name(&side)?;
+            }
+            this.onui = f.map(|f| lua.create_registry_value(f)).transpose()?;
+            Ok(())
+        });
+        // #endregion window methods
+
+        // #region pointer methods
+        // mousepos: w:mousepos() -> x, y（キャンバスのピクセル座標、不明ならnil）
+        methods.add_method("mousepos", |_, this, ()| {
+            Ok(match this.pointer_pos {
+                Some((x, y)) => (Some(x.floor() as i32), Some(y.floor() as i32)),
+                None => (None, None),
+            })
+        });
+        methods.add_method("ishovered", |_, this, ()| {
+            Ok(this.hovered)
+        });
+        // onclick: w:onclick(function(x, y, button, double) end)、nilで解除
+        methods.add_method_mut("onclick", |lua, this, f: Option<mlua::Function>| {
+            this.onclick = f.map(|f| lua.create_registry_value(f)).transpose()?;
+            Ok(())
+        });
+        // ondrag: w:ondrag(function(x, y, dx, dy, button, phase) end)、phaseは "start"/"drag"/"end"
+        methods.add_method_mut("ondrag", |lua, this, f: Option<mlua::Function>| {
+            this.ondrag = f.map(|f| lua.create_registry_value(f)).transpose()?;
+            Ok(())
+        });
+        // #endregion pointer methods
+    }
+}
+
+// 各ウィンドウに溜まったポインタイベントで onclick/ondrag/onclose を呼び、エラーを返す
+// ハンドラ内からウィンドウのメソッドを呼べるように、ロックを外してから呼び出す
+// エラーになったハンドラは毎回同じエラーを出さないように解除し、残りのイベント・ウィンドウは続けて処理する
+pub fn dispatch_window_events(lua: &Lua, windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>) -> Vec<LuaErrorReport> {
+    let windows = windows.lock().unwrap().clone();
+    let mut reports = Vec::new();
+    let handler = |key: &Option<mlua::RegistryKey>| key.as_ref().and_then(|key| lua.registry_value::<mlua::Function>(key).ok());
+    for w in windows.iter() {
+        let (events, mut onclick, mut ondrag, onclose) = {
+            let mut w = w.lock().unwrap();
+            let events = std::mem::take(&mut w.pointer_events);
+            let onclose = if std::mem::take(&mut w.close_requested) { Some(handler(&w.onclose)) } else { None };
+            (events, handler(&w.onclick), handler(&w.ondrag), onclose)
+        };
+        // ×ボタン: oncloseがfalseを返したら閉じない
+        match onclose {
+            Some(Some(f)) => match f.call::<_, Option<bool>>(w.clone()) {
+                Ok(Some(false)) => {}
+                Ok(_) => w.lock().unwrap().closed = true,
+                Err(e) => {
+                    w.lock().unwrap().onclose = None;
+                    reports.push(LuaErrorReport::new(lua, None, &e));
+                }
+            },
+            Some(None) => w.lock().unwrap().closed = true,
+            None => {}
+        }
+        for event in events {
+            let (result, is_click) = match event {
+                PointerEvent::Click { x, y, button, double } => (
+                    onclick.as_ref().map_or(Ok(()), |f| f.call::<_, ()>((x.floor() as i32, y.floor() as i32, button, double))),
+                    true,
+                ),
+                PointerEvent::Drag { x, y, dx, dy, button, phase } => (
+                    ondrag.as_ref().map_or(Ok(()), |f| f.call::<_, ()>((x.floor() as i32, y.floor() as i32, dx, dy, button, phase))),
+                    false,
+                ),
+            };
+            if let Err(e) = result {
+                let mut win = w.lock().unwrap();
+                if is_click {
+                    onclick = None;
+                    win.onclick = None;
+                } else {
+                    ondrag = None;
+                    win.ondrag = None;
+                }
+                reports.push(LuaErrorReport::new(lua, None, &e));
+            }
+        }
+    }
+    reports
+}
+
+// 生きているウィンドウと重ならないid（name, name#2, name#3, ...）
+fn unique_window_id(windows: &[Arc<Mutex<LuaWindow>>], name: &str) -> String {
+    let used = |id: &str| windows.iter().any(|win| {
+        let win = win.lock().unwrap();
+        !win.closed && win.id == id
+    });
+    let mut id = name.to_string();
+    let mut n = 2;
+    while used(&id) {
+        id = format!("{}#{}", name, n);
+        n += 1;
+    }
+    id
+}
+
+// 再ロード時に残すウィンドウに印を付ける（新しいスクリプトのcreate_windowで再利用できるようにする）
+pub fn keep_windows_for_reload(windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>) {
+    for win in windows.lock().unwrap().iter() {
+        win.lock().unwrap().kept = true;
+    }
+}
+
+// 再ロード後の最初のフレームで作り直されなかった残したウィンドウを閉じる
+pub fn close_kept_windows(windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>) {
+    for win in windows.lock().unwrap().iter() {
+        let mut win = win.lock().unwrap();
+        if win.kept {
+            win.closed = true;
+        }
     }
 }
 
+// close されたウィンドウを取り除き、そのidを返す（テクスチャの解放用）
+pub fn remove_closed_windows(windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>) -> Vec<String> {
+    let mut removed = Vec::new();
+    windows.lock().unwrap().retain(|w| {
+        let w = w.lock().unwrap();
+        if w.closed {
+            removed.push(w.id.clone());
+        }
+        !w.closed
+    });
+    removed
+}
+
 pub struct LuaEngine {
     pub lua: Lua,
 }
@@ -620,36 +565,396 @@ pub fn load_lua_coroutine<'lua>(lua: &'lua Lua, path: &str) -> LuaResult<Thread<
     // ファイル名をchunk名として渡すことでtracebackに反映される
     let chunk = lua.load(&code).set_name(path);
     let func = chunk.into_function()?;
-    lua.create_thread(func)
+    let thread = lua.create_thread(func)?;
+    crate::luatask::register_async_thread(lua, &thread)?;
+    Ok(thread)
+}
+
+// Luaエラーの表示用情報（メッセージ・traceback・失敗箇所のソース行）
+#[derive(Clone, Debug)]
+pub struct LuaErrorReport {
+    pub message: String,
+    pub traceback: String,
+    pub location: Option<(String, usize)>, // (チャンク名, 行番号)
+    pub source_line: Option<String>,
+}
+
+impl LuaErrorReport {
+    // threadはエラーで停止したcoroutine（ロード時のエラーではNone）
+    pub fn new(lua: &Lua, thread: Option<&Thread>, error: &mlua::Error) -> Self {
+        let text = error.to_string();
+        // mluaのエラーメッセージにtracebackが含まれていれば分離し、なければdebug.tracebackで取得
+        let (message, traceback) = match text.find("\nstack traceback:") {
+            Some(pos) => (text[..pos].to_string(), text[pos + 1..].to_string()),
+            None => {
+                let traceback = thread
+                    .and_then(|thread| {
+                        let debug: mlua::Table = lua.globals().get("debug").ok()?;
+                        let traceback: mlua::Function = debug.get("traceback").ok()?;
+                        traceback.call::<_, String>(thread.clone()).ok()
+                    })
+                    .unwrap_or_default();
+                (text, traceback)
+            }
+        };
+        let location = Self::parse_location(&message);
+        let source_line = location.as_ref().and_then(|(chunk, line)| {
+            let code = fs::read_to_string(chunk).ok()?;
+            code.lines().nth(line.checked_sub(1)?).map(|s| s.to_string())
+        });
+        Self { message, traceback, location, source_line }
+    }
+
+    // `[string "path"]:line:` または `path.lua:line:` 形式からエラー位置を取り出す
+    fn parse_location(message: &str) -> Option<(String, usize)> {
+        let parse_line = |rest: &str| -> Option<usize> {
+            let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+            digits.parse().ok()
+        };
+        if let Some(start) = message.find("[string \"") {
+            let rest = &message[start + 9..];
+            let end = rest.find("\"]:")?;
+            return Some((rest[..end].to_string(), parse_line(&rest[end + 3..])?));
+        }
+        let end = message.find(".lua:")? + 4;
+        let start = message[..end].rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        Some((message[start..end].to_string(), parse_line(&message[end + 1..])?))
+    }
+}
+
+impl std::fmt::Display for LuaErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        if let (Some((chunk, line)), Some(source)) = (&self.location, &self.source_line) {
+            writeln!(f, "{}:{}: {}", chunk, line, source.trim())?;
+        }
+        write!(f, "{}", self.traceback)
+    }
+}
+
+// ネイティブのフレームレート制御（egui.setfps）とフレーム情報
+// coroutine.yield() の戻り値として dt, frame, elapsed をスクリプトへ渡す
+pub struct FramePacer {
+    target_fps: f64, // 0以下で無制限
+    default_fps: f64, // コマンドラインの --fps（再ロード時に戻す）
+    frame: u64,
+    dt: f64,
+    start: std::time::Instant,
+    last_frame: std::time::Instant,
+    actual_fps: f64,
+    vsync_request: Option<bool>,
+    idle: bool, // egui.setidle(true): イベント・タイマー・invalidateがあるまでLuaを進めない
+    waiting_event: bool, // egui.waitevent() で待機中（そのフレームだけアイドル）
+    event_seq: u64, // 受け取ったウィンドウイベントの通し番号
+    event_pending: bool, // 前のフレーム以降にイベントがあった
+    invalidated: bool,
+}
+
+impl FramePacer {
+    // WaitUntilの起床誤差を吸収するため、残りがこれ未満ならsleepで待つ
+    const SLEEP_THRESHOLD: std::time::Duration = std::time::Duration::from_millis(2);
+    // フレーム間隔の上限（極端に小さいfpsでも時刻の計算があふれないようにする）
+    const MAX_FRAME_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+    pub fn new(target_fps: f64) -> Self {
+        let now = std::time::Instant::now();
+        Self {
+            target_fps,
+            default_fps: target_fps,
+            frame: 0,
+            dt: 0.0,
+            start: now,
+            last_frame: now,
+            actual_fps: 0.0,
+            vsync_request: None,
+            idle: false,
+            waiting_event: false,
+            event_seq: 0,
+            event_pending: false,
+            invalidated: false,
+        }
+    }
+    pub fn set_target_fps(&mut self, fps: f64) {
+        self.target_fps = fps;
+    }
+    pub fn get_target_fps(&self) -> f64 {
+        self.target_fps
+    }
+    // スクリプトの再ロード時に、前のスクリプトが変えた設定（setfps/setidle/waitevent/setvsync）をコマンドラインの値に戻す
+    pub fn reset_settings(&mut self) {
+        self.target_fps = self.default_fps;
+        self.idle = false;
+        self.waiting_event = false;
+        self.vsync_request = None;
+    }
+    // 次のフレームを開始する時刻（無制限ならNone）
+    pub fn next_frame_time(&self) -> Option<std::time::Instant> {
+        if self.target_fps > 0.0 {
+            let interval = std::time::Duration::try_from_secs_f64(1.0 / self.target_fps)
+                .map_or(Self::MAX_FRAME_INTERVAL, |interval| interval.min(Self::MAX_FRAME_INTERVAL));
+            Some(self.last_frame.checked_add(interval).unwrap_or(self.last_frame))
+        } else {
+            None
+        }
+    }
+    // フレーム開始時刻になっていればフレーム情報を更新してtrueを返す
+    pub fn begin_frame(&mut self) -> bool {
+        let now = std::time::Instant::now();
+        let scheduled = self.next_frame_time();
+        if let Some(next) = scheduled {
+            if now < next {
+                if next - now > Self::SLEEP_THRESHOLD {
+                    return false;
+                }
+                std::thread::sleep(next - now);
+            }
+        }
+        self.advance();
+        // 予定時刻からの遅れが1フレーム未満なら予定時刻を基準にして、起床の遅れが蓄積しないようにする
+        if let Some(next) = scheduled {
+            if self.last_frame.saturating_duration_since(next).as_secs_f64() * self.target_fps < 1.0 {
+                self.last_frame = next;
+            }
+        }
+        true
+    }
+    // 待機せずにフレームを進める（headless用）
+    pub fn advance(&mut self) {
+        let now = std::time::Instant::now();
+        self.dt = if self.frame == 0 { 0.0 } else { (now - self.last_frame).as_secs_f64() };
+        if self.dt > 0.0 {
+            // 表示用に平滑化
+            self.actual_fps = self.actual_fps * 0.9 + (1.0 / self.dt) * 0.1;
+        }
+        self.last_frame = now;
+        self.frame += 1;
+        // 起床条件はこのフレームのresume中に登録し直される
+        self.event_pending = false;
+        self.invalidated = false;
+        self.waiting_event = false;
+        crate::luatask::clear_wake();
+    }
+    // ウィンドウイベントを受け取った（アイドル中なら次のフレームを実行する）
+    pub fn notify_event(&mut self) {
+        self.event_seq += 1;
+        self.event_pending = true;
+    }
+    // アイドル中でまだ起きる必要がなければ待機する期限を返す（Some(None)は次のイベントまで）
+    // Noneならフレームを実行する
+    pub fn idle_wait(&self) -> Option<Option<std::time::Instant>> {
+        if !(self.idle || self.waiting_event) || self.event_pending || self.invalidated {
+            return None;
+        }
+        match crate::luatask::next_wake() {
+            Some(wake) if wake <= std::time::Instant::now() => None,
+            wake => Some(wake),
+        }
+    }
+    // coroutine.yield() の戻り値 (dt, frame, elapsed)
+    pub fn frame_args(&self) -> (f64, u64, f64) {
+        (self.dt, self.frame, self.start.elapsed().as_secs_f64())
+    }
+    pub fn get_actual_fps(&self) -> f64 {
+        self.actual_fps
+    }
+    // egui.setvsync による要求（main.rsでsurfaceを再設定する）
+    pub fn take_vsync_request(&mut self) -> Option<bool> {
+        self.vsync_request.take()
+    }
+}
+
+// egui.waitevent の待機（イベントが来たらtrue、タイムアウトならfalse）
+struct EventWait {
+    pacer: Arc<Mutex<FramePacer>>,
+    start_seq: u64,
+    deadline: Option<std::time::Instant>,
+}
+
+impl std::future::Future for EventWait {
+    type Output = bool;
+    fn poll(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<bool> {
+        let mut pacer = self.pacer.lock().unwrap();
+        if pacer.event_seq != self.start_seq {
+            return std::task::Poll::Ready(true);
+        }
+        if let Some(deadline) = self.deadline {
+            if std::time::Instant::now() >= deadline {
+                return std::task::Poll::Ready(false);
+            }
+            crate::luatask::request_wake(deadline);
+        }
+        pacer.waiting_event = true;
+        std::task::Poll::Pending
+    }
+}
+
+// egui.setfps / egui.getfps / egui.getactualfps / egui.getframe / egui.setvsync
+// egui.setidle / egui.isidle / egui.invalidate / egui.waitevent を登録（register_eguiの後に呼ぶ）
+pub fn register_frame_pacer(lua: &Lua, pacer: Arc<Mutex<FramePacer>>) -> LuaResult<()> {
+    let egui_table: mlua::Table = lua.globals().get("egui")?;
+    let p = pacer.clone();
+    egui_table.set("setfps", lua.create_function(move |_, fps: Option<f64>| {
+        let fps = fps.unwrap_or(0.0);
+        if !fps.is_finite() {
+            return Err(mlua::Error::external("fps must be a finite number"));
+        }
+        p.lock().unwrap().set_target_fps(fps);
+        Ok(())
+    })?)?;
+    let p = pacer.clone();
+    egui_table.set("getfps", lua.create_function(move |_, ()| {
+        Ok(p.lock().unwrap().get_target_fps())
+    })?)?;
+    let p = pacer.clone();
+    egui_table.set("getactualfps", lua.create_function(move |_, ()| {
+        Ok(p.lock().unwrap().get_actual_fps())
+    })?)?;
+    let p = pacer.clone();
+    egui_table.set("getframe", lua.create_function(move |_, ()| {
+        Ok(p.lock().unwrap().frame_args())
+    })?)?;
+    let p = pacer.clone();
+    egui_table.set("setvsync", lua.create_function(move |_, on: bool| {
+        p.lock().unwrap().vsync_request = Some(on);
+        Ok(())
+    })?)?;
+    // setidle: trueならイベント・タイマー(sleep, task.wait)・invalidateがあるときだけcoroutineを進める
+    let p = pacer.clone();
+    egui_table.set("setidle", lua.create_function(move |_, idle: bool| {
+        p.lock().unwrap().idle = idle;
+        Ok(())
+    })?)?;
+    let p = pacer.clone();
+    egui_table.set("isidle", lua.create_function(move |_, ()| {
+        Ok(p.lock().unwrap().idle)
+    })?)?;
+    // invalidate: アイドル中でも次のフレームを実行する
+    let p = pacer.clone();
+    egui_table.set("invalidate", lua.create_function(move |_, ()| {
+        p.lock().unwrap().invalidated = true;
+        Ok(())
+    })?)?;
+    // waitevent([timeout]): 入力などのイベントが来るまで待つ（待機中はアイドル）、イベントならtrue・タイムアウトならfalse
+    let p = pacer.clone();
+    egui_table.set("waitevent", lua.create_async_function(move |_, timeout: Option<f64>| {
+        let pacer = p.clone();
+        async move {
+            let start_seq = pacer.lock().unwrap().event_seq;
+            let deadline = timeout.and_then(crate::luatask::deadline_after);
+            Ok(EventWait { pacer, start_seq, deadline }.await)
+        }
+    })?)?;
+    Ok(())
+}
+
+// Luaファイルの更新検知（ホットリロード用、更新時刻をポーリングする）
+pub struct ScriptWatcher {
+    path: String,
+    modified: Option<std::time::SystemTime>,
+    last_check: std::time::Instant,
+}
+
+impl ScriptWatcher {
+    const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+    // 次に更新時刻を確認する時刻（アイドル中の待機の上限）
+    pub fn next_check(&self) -> std::time::Instant {
+        self.last_check + Self::CHECK_INTERVAL
+    }
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            modified: Self::modified_time(path),
+            last_check: std::time::Instant::now(),
+        }
+    }
+    fn modified_time(path: &str) -> Option<std::time::SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+    // 前回チェック時から更新されていればtrue（チェックはCHECK_INTERVAL間隔）
+    pub fn poll_changed(&mut self) -> bool {
+        if self.last_check.elapsed() < Self::CHECK_INTERVAL {
+            return false;
+        }
+        self.last_check = std::time::Instant::now();
+        let modified = Self::modified_time(&self.path);
+        if modified.is_some() && modified != self.modified {
+            self.modified = modified;
+            return true;
+        }
+        false
+    }
+}
+
+// リロード前に egui.onreload() を呼び、その戻り値（引き継ぐ状態）を返す
+// onreloadは新しいスクリプトが再設定するまで nil に戻す
+pub fn call_onreload<'lua>(lua: &'lua Lua) -> LuaResult<mlua::Value<'lua>> {
+    let egui_table: mlua::Table = lua.globals().get("egui")?;
+    let handler: Option<mlua::Function> = egui_table.get("onreload")?;
+    egui_table.set("onreload", mlua::Value::Nil)?;
+    match handler {
+        Some(f) => f.call(()),
+        None => Ok(mlua::Value::Nil),
+    }
 }
 
 // egui Lua API登録（ダミー実装、必要に応じて本実装に変更）
 pub fn register_egui(lua: &Lua, windows: Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>) -> LuaResult<()> {
     let egui_table = lua.create_table()?;
-    let windows = windows.clone();
+    let create_windows = windows.clone();
     egui_table.set(
         "create_window",
         lua.create_function(
-            move |_, (name, width, height): (String, Option<usize>, Option<usize>)| {
+            move |_, (name, width, height, options): (String, Option<usize>, Option<usize>, Option<mlua::Table>)| {
                 let w = width.unwrap_or(320);
                 let h = height.unwrap_or(240);
-                let win = Arc::new(Mutex::new(LuaWindow {
-                    id: name.clone(),
-                    x: 0,
-                    y: 0,
-                    width: w,
-                    height: h,
-                    buffer: vec![0; w * h * 4],
-                    text_color: (255, 255, 255, 255),
-                    text_font_size: 16, // デフォルトサイズ
-                    fillpaint_stack: Vec::new(),
-                    fillpaint_visited: vec![false; (w * h) as usize],
-                }));
-                windows.lock().unwrap().push(win.clone());
+                let mut windows = create_windows.lock().unwrap();
+                // 再ロード時に残したウィンドウは同名・同サイズなら再利用し、サイズが違えば取り除いて作り直す
+                let kept = windows.iter().position(|win| {
+                    let win = win.lock().unwrap();
+                    win.kept && !win.closed && win.id == name
+                });
+                let mut reused = None;
+                if let Some(i) = kept {
+                    let mut kept_win = windows[i].lock().unwrap();
+                    if kept_win.width == w && kept_win.height == h {
+                        kept_win.reset_for_reload();
+                        drop(kept_win);
+                        reused = Some(windows[i].clone());
+                    } else {
+                        kept_win.closed = true;
+                        drop(kept_win);
+                        windows.remove(i);
+                    }
+                }
+                let win = match reused {
+                    Some(win) => win,
+                    None => {
+                        // idはテクスチャとegui Windowのキーなので、同名のウィンドウがあれば番号を付けて重ならないようにする
+                        let id = unique_window_id(&windows, &name);
+                        let mut new_win = LuaWindow::new(&id, w, h);
+                        new_win.title = name.clone();
+                        let win = Arc::new(Mutex::new(new_win));
+                        windows.push(win.clone());
+                        win
+                    }
+                };
+                drop(windows);
+                if let Some(options) = options {
+                    win.lock().unwrap().apply_options(&options)?;
+                }
                 Ok(win)
             },
         )?,
     )?;
+    // hoveredwindow: カーソル下のキャンバスを持つウィンドウ（なければnil）
+    let hovered_windows = windows.clone();
+    egui_table.set(
+        "hoveredwindow",
+        lua.create_function(move |_, ()| {
+            Ok(hovered_windows.lock().unwrap().iter().find(|win| win.lock().unwrap().hovered).cloned())
+        })?,
+    )?;
     lua.globals().set("egui", egui_table)?;
     Ok(())
 }
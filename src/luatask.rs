@@ -0,0 +1,366 @@
+//! taskモジュール（Luaから利用）
+//! - spawn, cancel, wait, wait_frames
+//! - タスクはcoroutineとしてフレームごと(MainEventsCleared)にresumeされる
+//! - wait/wait_frames は mlua の async 関数で、待機中はcoroutineをyieldしてイベントループに処理を戻す
+//! - 待機中のFutureは起床時刻を登録する（アイドルモードでイベントループが待機する時間の上限になる）
+
+use crate::luamod::LuaErrorReport;
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue, RegistryKey, Result as LuaResult, Thread, ThreadStatus, UserData, UserDataMethods, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// アイドル中に起床すべき最も早い時刻（待機中のFutureがpollのたびに登録し、フレーム開始時に消す）
+static NEXT_WAKE: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub fn request_wake(at: Instant) {
+    let mut next = NEXT_WAKE.lock().unwrap();
+    *next = Some(next.map_or(at, |next| next.min(at)));
+}
+pub fn next_wake() -> Option<Instant> {
+    *NEXT_WAKE.lock().unwrap()
+}
+pub fn clear_wake() {
+    *NEXT_WAKE.lock().unwrap() = None;
+}
+
+// 非同期関数で待機できるスレッド（メインスクリプトとタスク）の表（キーが弱参照の表をレジストリに置く）
+// スクリプトが自分で作ったcoroutineはイベントループがresumeしないので、そこでは待機をyieldにしない
+const ASYNC_THREADS: &str = "rust_mlua2_async_threads";
+
+fn async_threads(lua: &Lua) -> LuaResult<mlua::Table<'_>> {
+    if let Some(table) = lua.named_registry_value::<Option<mlua::Table>>(ASYNC_THREADS)? {
+        return Ok(table);
+    }
+    let table = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set("__mode", "k")?;
+    table.set_metatable(Some(meta));
+    lua.set_named_registry_value(ASYNC_THREADS, table.clone())?;
+    Ok(table)
+}
+
+// イベントループ・スケジューラがresumeするスレッドとして登録する
+pub fn register_async_thread(lua: &Lua, thread: &Thread) -> LuaResult<()> {
+    async_threads(lua)?.set(thread.clone(), true)
+}
+
+// 登録したスレッドの中では async_fn を、それ以外（REPL・イベントハンドラ・スクリプトのcoroutine）では fallback を呼ぶ関数
+pub fn async_or_fallback<'lua>(lua: &'lua Lua, name: &str, async_fn: Function<'lua>, fallback: Function<'lua>) -> LuaResult<Function<'lua>> {
+    lua.load(
+        r#"
+        local async_fn, fallback, threads = ...
+        local running = coroutine.running
+        return function(...)
+            local co = running()
+            if co and threads[co] then
+                return async_fn(...)
+            end
+            return fallback(...)
+        end
+        "#,
+    )
+    .set_name(name)
+    .call((async_fn, fallback, async_threads(lua)?))
+}
+
+// 指定フレーム数だけPendingを返すFuture（1回のpoll = 1回のresume = 1フレーム）
+pub struct FrameWait {
+    remaining: u64,
+}
+
+impl FrameWait {
+    pub fn new(frames: u64) -> Self {
+        Self { remaining: frames }
+    }
+}
+
+impl Future for FrameWait {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        if self.remaining == 0 {
+            return Poll::Ready(());
+        }
+        self.remaining -= 1;
+        // フレーム単位の待機中はアイドルにしない
+        request_wake(Instant::now());
+        Poll::Pending
+    }
+}
+
+// 今から secs 秒後の時刻（math.hugeなど表現できない時間はNone = 期限なし）
+pub fn deadline_after(secs: f64) -> Option<Instant> {
+    Duration::try_from_secs_f64(secs.max(0.0)).ok().and_then(|d| Instant::now().checked_add(d))
+}
+
+// 指定時刻までPendingを返すFuture（resumeのたびに時刻を確認する、期限なしならずっと待つ）
+pub struct Deadline {
+    deadline: Option<Instant>,
+}
+
+impl Deadline {
+    pub fn after(secs: f64) -> Self {
+        Self { deadline: deadline_after(secs) }
+    }
+}
+
+impl Future for Deadline {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Poll::Ready(()),
+            Some(deadline) => {
+                request_wake(deadline);
+                Poll::Pending
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskStatus {
+    Suspended,
+    Running,
+    Dead,
+    Error,
+    Cancelled,
+}
+
+impl TaskStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Suspended => "suspended",
+            TaskStatus::Running => "running",
+            TaskStatus::Dead => "dead",
+            TaskStatus::Error => "error",
+            TaskStatus::Cancelled => "cancelled",
+        }
+    }
+    fn is_finished(&self) -> bool {
+        matches!(self, TaskStatus::Dead | TaskStatus::Error | TaskStatus::Cancelled)
+    }
+}
+
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: String,
+    pub status: TaskStatus,
+}
+
+struct TaskEntry {
+    info: Arc<Mutex<TaskInfo>>,
+    thread: RegistryKey,
+}
+
+// Luaに返すタスクハンドル
+pub struct LuaTask(Arc<Mutex<TaskInfo>>);
+
+impl LuaTask {
+    fn cancel(&self) {
+        let mut info = self.0.lock().unwrap();
+        if !info.status.is_finished() {
+            info.status = TaskStatus::Cancelled;
+        }
+    }
+}
+
+impl UserData for LuaTask {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("getname", |_, this, ()| {
+            Ok(this.0.lock().unwrap().name.clone())
+        });
+        methods.add_method("getid", |_, this, ()| {
+            Ok(this.0.lock().unwrap().id)
+        });
+        methods.add_method("status", |_, this, ()| {
+            Ok(this.0.lock().unwrap().status.as_str())
+        });
+        methods.add_method("cancel", |_, this, ()| {
+            this.cancel();
+            Ok(())
+        });
+    }
+}
+
+pub struct TaskScheduler {
+    tasks: Vec<Arc<TaskEntry>>,
+    next_id: u64,
+    errors: Vec<LuaErrorReport>,
+}
+
+impl TaskScheduler {
+    pub fn new() -> Self {
+        Self { tasks: Vec::new(), next_id: 1, errors: Vec::new() }
+    }
+    // 終了・cancel済みでないタスク数
+    pub fn count(&self) -> usize {
+        self.tasks.iter().filter(|entry| !entry.info.lock().unwrap().status.is_finished()).count()
+    }
+    // 全タスクを破棄（スクリプトの再ロード時）
+    pub fn clear(&mut self) {
+        for entry in &self.tasks {
+            entry.info.lock().unwrap().status = TaskStatus::Cancelled;
+        }
+        self.tasks.clear();
+        self.errors.clear();
+    }
+}
+
+// タスクを1回resumeし、エラーならタスク名付きのLuaErrorReportを返す
+fn resume_task<'lua>(lua: &'lua Lua, entry: &TaskEntry, args: MultiValue<'lua>) -> Option<LuaErrorReport> {
+    let thread: Thread = match lua.registry_value(&entry.thread) {
+        Ok(thread) => thread,
+        Err(e) => {
+            entry.info.lock().unwrap().status = TaskStatus::Error;
+            return Some(LuaErrorReport::new(lua, None, &e));
+        }
+    };
+    entry.info.lock().unwrap().status = TaskStatus::Running;
+    let result = thread.resume::<_, ()>(args);
+    let mut info = entry.info.lock().unwrap();
+    match result {
+        Ok(()) => {
+            // resume中に自身がcancelされた場合はその状態を保つ
+            if info.status == TaskStatus::Running {
+                info.status = if thread.status() == ThreadStatus::Resumable {
+                    TaskStatus::Suspended
+                } else {
+                    TaskStatus::Dead
+                };
+            }
+            None
+        }
+        Err(e) => {
+            info.status = TaskStatus::Error;
+            let mut report = LuaErrorReport::new(lua, Some(&thread), &e);
+            report.message = format!("[task {}] {}", info.name, report.message);
+            Some(report)
+        }
+    }
+}
+
+// 全タスクを1フレーム分進め、発生したエラーを返す（エラーになったタスクだけが終了する）
+// frame_argsはcoroutine.yield()の戻り値 (dt, frame, elapsed)
+// resume中のタスクがtask.spawnできるように、ロックを保持したままresumeしない
+pub fn resume_all(scheduler: &Arc<Mutex<TaskScheduler>>, lua: &Lua, frame_args: (f64, u64, f64)) -> Vec<LuaErrorReport> {
+    let tasks = scheduler.lock().unwrap().tasks.clone();
+    let mut errors = Vec::new();
+    for entry in &tasks {
+        if entry.info.lock().unwrap().status != TaskStatus::Suspended {
+            continue;
+        }
+        let args = frame_args.into_lua_multi(lua).unwrap_or_default();
+        if let Some(report) = resume_task(lua, entry, args) {
+            errors.push(report);
+        }
+    }
+    let mut scheduler = scheduler.lock().unwrap();
+    scheduler.tasks.retain(|entry| !entry.info.lock().unwrap().status.is_finished());
+    let mut reports: Vec<LuaErrorReport> = scheduler.errors.drain(..).collect();
+    reports.extend(errors);
+    drop(scheduler);
+    lua.expire_registry_values();
+    reports
+}
+
+pub fn register_task(lua: &Lua, scheduler: Arc<Mutex<TaskScheduler>>) -> LuaResult<()> {
+    let task_mod = lua.create_table()?;
+
+    // task.spawn([name,] fn, ...) 新しいタスクを作成し、最初のyieldまで即座に実行する
+    let spawn_scheduler = scheduler.clone();
+    task_mod.set(
+        "spawn",
+        lua.create_function(move |lua, args: MultiValue| {
+            let mut args = args.into_vec();
+            let (name, func) = match (args.first(), args.get(1)) {
+                (Some(Value::String(name)), Some(Value::Function(func))) => {
+                    let named = (Some(name.to_str()?.to_string()), func.clone());
+                    args.drain(..2);
+                    named
+                }
+                (Some(Value::Function(func)), _) => {
+                    let func = func.clone();
+                    args.remove(0);
+                    (None, func)
+                }
+                _ => return Err(mlua::Error::external("task.spawn: function expected")),
+            };
+            let name = name.unwrap_or_else(|| task_name(&func));
+            let thread = lua.create_thread(func)?;
+            register_async_thread(lua, &thread)?;
+            let info = {
+                let mut scheduler = spawn_scheduler.lock().unwrap();
+                let id = scheduler.next_id;
+                scheduler.next_id += 1;
+                Arc::new(Mutex::new(TaskInfo { id, name, status: TaskStatus::Suspended }))
+            };
+            let entry = Arc::new(TaskEntry { info: info.clone(), thread: lua.create_registry_value(thread)? });
+            let error = resume_task(lua, &entry, MultiValue::from_vec(args));
+            let mut scheduler = spawn_scheduler.lock().unwrap();
+            // 初回のresumeのエラーは次の resume_all で呼び出し側が報告する
+            if let Some(report) = error {
+                scheduler.errors.push(report);
+            } else if !entry.info.lock().unwrap().status.is_finished() {
+                scheduler.tasks.push(entry);
+            }
+            Ok(LuaTask(info))
+        })?,
+    )?;
+
+    // task.cancel(t) タスクを停止する（実行中の自身をcancelした場合は次のyieldで停止）
+    task_mod.set(
+        "cancel",
+        lua.create_function(|_, t: mlua::AnyUserData| {
+            t.borrow::<LuaTask>()?.cancel();
+            Ok(())
+        })?,
+    )?;
+
+    // task.count() 実行中のタスク数
+    let count_scheduler = scheduler.clone();
+    task_mod.set(
+        "count",
+        lua.create_function(move |_, ()| {
+            Ok(count_scheduler.lock().unwrap().count())
+        })?,
+    )?;
+
+    // task.wait_frames(n) nフレーム待つ（デフォルト1）。メインスクリプトとタスクの中でだけ使える
+    let wait_frames = lua.create_async_function(|_, frames: Option<u64>| async move {
+        FrameWait::new(frames.unwrap_or(1)).await;
+        Ok(())
+    })?;
+    let no_frames = lua.create_function(|_, _: MultiValue| -> LuaResult<()> {
+        Err(mlua::Error::external("task.wait_frames: can only wait in the main script or a task"))
+    })?;
+    task_mod.set("wait_frames", async_or_fallback(lua, "task.wait_frames", wait_frames, no_frames)?)?;
+
+    // task.wait(seconds) 指定秒数待ち、実際に経過した秒数を返す
+    // メインスクリプトとタスク以外（スクリプトが作ったcoroutineなど）ではスレッドをブロックして待つ
+    let wait = lua.create_async_function(|_, secs: Option<f64>| async move {
+        let start = Instant::now();
+        Deadline::after(secs.unwrap_or(0.0)).await;
+        Ok(start.elapsed().as_secs_f64())
+    })?;
+    let blocking_wait = lua.create_function(|_, secs: Option<f64>| {
+        let start = Instant::now();
+        crate::luamod::blocking_sleep(secs.unwrap_or(0.0));
+        Ok(start.elapsed().as_secs_f64())
+    })?;
+    task_mod.set("wait", async_or_fallback(lua, "task.wait", wait, blocking_wait)?)?;
+
+    lua.globals().set("task", task_mod)?;
+    Ok(())
+}
+
+// 名前省略時のタスク名（定義位置）
+fn task_name(func: &Function) -> String {
+    let info = func.info();
+    match (info.short_src, info.line_defined) {
+        (Some(src), Some(line)) => format!("{}:{}", src, line),
+        _ => "task".to_string(),
+    }
+}
@@ -0,0 +1,202 @@
+//! eguiウィジェットをLuaから使うためのUI API
+//! - w:onui(function(ui) ... end) で登録した関数が毎フレーム（描画時）に呼ばれる
+//! - ui はそのフレームの間だけ有効（mluaのscopeで作るので保持しても使えない）
+//! - 値を持つウィジェットは新しい値と変化したかを返す（即時モードなので値はLua側で保持する）
+//!   例: gravity = ui:slider("gravity", gravity, 0, 10)
+
+use mlua::{Function, Lua, Result as LuaResult, UserData, UserDataMethods};
+
+// キャンバスに対するUIの配置
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UiSide {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+impl UiSide {
+    pub fn from_name(name: &str) -> LuaResult<Self> {
+        match name {
+            "top" => Ok(UiSide::Top),
+            "bottom" => Ok(UiSide::Bottom),
+            "left" => Ok(UiSide::Left),
+            "right" => Ok(UiSide::Right),
+            _ => Err(mlua::Error::external(format!("unknown side: {} (top, bottom, left or right)", name))),
+        }
+    }
+}
+
+pub struct LuaUi<'a> {
+    ui: &'a mut egui::Ui,
+}
+
+// uiをLuaのuserdataとして f(ui) を呼ぶ（userdataはこの呼び出しの間だけ有効）
+pub fn call_ui<'lua>(lua: &'lua Lua, ui: &mut egui::Ui, f: &Function<'lua>) -> LuaResult<()> {
+    lua.scope(|scope| {
+        let ud = scope.create_nonstatic_userdata(LuaUi { ui })?;
+        f.call::<_, ()>(ud)
+    })
+}
+
+// キャンバスとUIをsideに従って並べ、キャンバスのResponseを返す
+// fit（キャンバスが残りの領域いっぱいに広がる）の場合は、UIを先に配置してから残りにキャンバスを置く
+pub fn show_with_ui(
+    ui: &mut egui::Ui,
+    side: UiSide,
+    fit: bool,
+    run_ui: impl FnOnce(&mut egui::Ui),
+    add_canvas: impl FnOnce(&mut egui::Ui) -> egui::Response,
+) -> egui::Response {
+    match (side, fit) {
+        (UiSide::Top, _) => ui.vertical(|ui| {
+            ui.vertical(run_ui);
+            add_canvas(ui)
+        }).inner,
+        (UiSide::Bottom, false) => ui.vertical(|ui| {
+            let response = add_canvas(ui);
+            ui.vertical(run_ui);
+            response
+        }).inner,
+        (UiSide::Bottom, true) => ui.with_layout(egui::Layout::bottom_up(egui::Align::Min), |ui| {
+            ui.vertical(run_ui);
+            add_canvas(ui)
+        }).inner,
+        (UiSide::Left, _) => ui.horizontal_top(|ui| {
+            ui.vertical(run_ui);
+            add_canvas(ui)
+        }).inner,
+        (UiSide::Right, false) => ui.horizontal_top(|ui| {
+            let response = add_canvas(ui);
+            ui.vertical(run_ui);
+            response
+        }).inner,
+        (UiSide::Right, true) => ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+            ui.vertical(run_ui);
+            add_canvas(ui)
+        }).inner,
+    }
+}
+
+impl<'a> UserData for LuaUi<'a> {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        // #region text widgets
+        methods.add_method_mut("label", |_, this, text: String| {
+            this.ui.label(text);
+            Ok(())
+        });
+        methods.add_method_mut("heading", |_, this, text: String| {
+            this.ui.heading(text);
+            Ok(())
+        });
+        methods.add_method_mut("separator", |_, this, ()| {
+            this.ui.separator();
+            Ok(())
+        });
+        methods.add_method_mut("space", |_, this, amount: Option<f32>| {
+            this.ui.add_space(amount.unwrap_or(8.0));
+            Ok(())
+        });
+        // #endregion text widgets
+
+        // #region input widgets
+        // button: ui:button(text) -> clicked
+        methods.add_method_mut("button", |_, this, text: String| {
+            Ok(this.ui.button(text).clicked())
+        });
+        // checkbox: ui:checkbox(label, checked) -> checked, changed
+        methods.add_method_mut("checkbox", |_, this, (label, mut checked): (String, bool)| {
+            let changed = this.ui.checkbox(&mut checked, label).changed();
+            Ok((checked, changed))
+        });
+        // radio: ui:radio(label, selected) -> clicked
+        methods.add_method_mut("radio", |_, this, (label, selected): (String, bool)| {
+            Ok(this.ui.radio(selected, label).clicked())
+        });
+        // slider: ui:slider(label, value, min, max[, step]) -> value, changed
+        methods.add_method_mut(
+            "slider",
+            |_, this, (label, mut value, min, max, step): (String, f64, f64, f64, Option<f64>)| {
+                let mut slider = egui::Slider::new(&mut value, min..=max).text(label);
+                if let Some(step) = step {
+                    slider = slider.step_by(step);
+                }
+                let changed = this.ui.add(slider).changed();
+                Ok((value, changed))
+            },
+        );
+        // dragvalue: ui:dragvalue(label, value[, speed]) -> value, changed
+        methods.add_method_mut("dragvalue", |_, this, (label, mut value, speed): (String, f64, Option<f64>)| {
+            let changed = this.ui.horizontal(|ui| {
+                ui.label(label);
+                ui.add(egui::DragValue::new(&mut value).speed(speed.unwrap_or(1.0))).changed()
+            }).inner;
+            Ok((value, changed))
+        });
+        // textedit: ui:textedit(text[, multiline]) -> text, changed
+        methods.add_method_mut("textedit", |_, this, (mut text, multiline): (String, Option<bool>)| {
+            let changed = if multiline.unwrap_or(false) {
+                this.ui.text_edit_multiline(&mut text).changed()
+            } else {
+                this.ui.text_edit_singleline(&mut text).changed()
+            };
+            Ok((text, changed))
+        });
+        // combobox: ui:combobox(label, selected, {item, ...}) -> selected, changed
+        methods.add_method_mut(
+            "combobox",
+            |_, this, (label, mut selected, items): (String, String, Vec<String>)| {
+                let before = selected.clone();
+                egui::ComboBox::from_label(label).selected_text(before.as_str()).show_ui(this.ui, |ui| {
+                    for item in items {
+                        ui.selectable_value(&mut selected, item.clone(), item);
+                    }
+                });
+                let changed = selected != before;
+                Ok((selected, changed))
+            },
+        );
+        // color: ui:color(r, g, b) -> r, g, b, changed
+        methods.add_method_mut("color", |_, this, (r, g, b): (u8, u8, u8)| {
+            let mut rgb = [r, g, b];
+            let changed = this.ui.color_edit_button_srgb(&mut rgb).changed();
+            Ok((rgb[0], rgb[1], rgb[2], changed))
+        });
+        // progress: ui:progress(fraction[, text])
+        methods.add_method_mut("progress", |_, this, (fraction, text): (f32, Option<String>)| {
+            let mut bar = egui::ProgressBar::new(fraction.clamp(0.0, 1.0));
+            if let Some(text) = text {
+                bar = bar.text(text);
+            }
+            this.ui.add(bar);
+            Ok(())
+        });
+        // #endregion input widgets
+
+        // #region layout
+        // 子の関数には新しいuiが渡される（外側のuiはその間使えない）
+        methods.add_method_mut("horizontal", |lua, this, f: Function| {
+            this.ui.horizontal(|ui| call_ui(lua, ui, &f)).inner
+        });
+        methods.add_method_mut("vertical", |lua, this, f: Function| {
+            this.ui.vertical(|ui| call_ui(lua, ui, &f)).inner
+        });
+        methods.add_method_mut("group", |lua, this, f: Function| {
+            this.ui.group(|ui| call_ui(lua, ui, &f)).inner
+        });
+        // collapsing: ui:collapsing(title, function(ui) end[, default_open]) -> 開いているか
+        methods.add_method_mut(
+            "collapsing",
+            |lua, this, (title, f, default_open): (String, Function, Option<bool>)| {
+                let response = egui::CollapsingHeader::new(title)
+                    .default_open(default_open.unwrap_or(false))
+                    .show(this.ui, |ui| call_ui(lua, ui, &f));
+                match response.body_returned {
+                    Some(result) => result.map(|_| true),
+                    None => Ok(false),
+                }
+            },
+        );
+        // #endregion layout
+    }
+}
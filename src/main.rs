@@ -2,15 +2,44 @@ mod luamod;
 mod luaimage;
 mod luamidi;
 mod luagraphic;
+mod luatask;
+mod luainput;
+mod luaui;
+mod luacanvas;
+mod luafont;
 use clap::Parser;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
-use egui::{ColorImage, TextureHandle, TextureOptions};
+use egui::{ColorImage, TextureHandle};
 use egui_winit::winit::window::WindowBuilder;
 use egui_winit::winit::event_loop::{EventLoop, ControlFlow};
 use egui_winit::winit::event::{Event, WindowEvent};
-use luamod::{LuaEngine, register_egui, LuaWindow, load_lua_coroutine};
+use luamod::{LuaEngine, register_egui, LuaWindow, load_lua_coroutine, ScriptWatcher, LuaErrorReport, FramePacer};
 use mlua::{Thread, ThreadStatus};
+use luatask::TaskScheduler;
+use luainput::InputState;
+
+// wgpuのPresentMode（CLI指定用）
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum PresentModeArg {
+    Immediate,
+    Fifo,
+    Mailbox,
+    AutoVsync,
+    AutoNoVsync,
+}
+
+impl From<PresentModeArg> for egui_wgpu::wgpu::PresentMode {
+    fn from(mode: PresentModeArg) -> Self {
+        match mode {
+            PresentModeArg::Immediate => egui_wgpu::wgpu::PresentMode::Immediate,
+            PresentModeArg::Fifo => egui_wgpu::wgpu::PresentMode::Fifo,
+            PresentModeArg::Mailbox => egui_wgpu::wgpu::PresentMode::Mailbox,
+            PresentModeArg::AutoVsync => egui_wgpu::wgpu::PresentMode::AutoVsync,
+            PresentModeArg::AutoNoVsync => egui_wgpu::wgpu::PresentMode::AutoNoVsync,
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -18,11 +47,162 @@ struct Args {
     /// Luaファイル（省略時REPL）
     lua_file: Option<String>,
     
+    /// Luaファイルの更新を監視し、保存時にスクリプトを再ロードする
+    #[arg(long)]
+    watch: bool,
+
+    /// 再ロード時に既存のウィンドウを閉じずに残す
+    #[arg(long)]
+    keep_windows: bool,
+
+    /// 目標フレームレート（0で無制限、Luaからは egui.setfps で変更）
+    #[arg(long, default_value_t = 0.0)]
+    fps: f64,
+
+    /// 画面の更新方式（fifo/auto-vsyncでVSync ON）
+    #[arg(long, value_enum, default_value_t = PresentModeArg::Immediate)]
+    present_mode: PresentModeArg,
+
+    /// ウィンドウ/GPUを使わずオフスクリーンで実行し、LuaWindowをPNGへ書き出す
+    #[arg(long)]
+    headless: bool,
+
+    /// headless時に進めるフレーム数（coroutine.yield()の回数）
+    #[arg(long, default_value_t = 1)]
+    frames: u64,
+
+    /// headless時のPNG出力先ディレクトリ
+    #[arg(long, default_value = ".")]
+    output: String,
+
+    /// headless時に毎フレーム保存する（省略時は最終フレームのみ）
+    #[arg(long)]
+    save_all_frames: bool,
+
     /// 残りのコマンドライン引数
     #[arg(trailing_var_arg = true)]
     rest_args: Vec<String>,
 }
 
+// ウィンドウIDをファイル名に使えるように置換
+fn sanitize_file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            '\\' | '/' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_whitespace() || c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// 全LuaWindowのbufferをPNGとして保存
+fn save_windows_png(windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>, output: &str, frame: Option<u64>) -> Result<(), String> {
+    let windows_lock = windows.lock().unwrap();
+    for (i, w) in windows_lock.iter().enumerate() {
+        let w = w.lock().unwrap();
+        if !w.visible {
+            continue;
+        }
+        let name = match frame {
+            Some(frame) => format!("{:02}_{}_{:06}.png", i, sanitize_file_name(&w.id), frame),
+            None => format!("{:02}_{}.png", i, sanitize_file_name(&w.id)),
+        };
+        let path = std::path::Path::new(output).join(name);
+        let img = image::RgbaImage::from_raw(w.width as u32, w.height as u32, w.buffer.clone())
+            .ok_or_else(|| format!("invalid buffer size: {}", w.id))?;
+        img.save(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        println!("[headless] saved {}", path.display());
+    }
+    Ok(())
+}
+
+// スクリプトを再ロードし、新しいcoroutineと egui.onreload() の戻り値を返す
+// 新しいファイルのロードに失敗した場合は何もせずエラーを返す（実行中のスクリプトはそのまま）
+fn reload_lua_coroutine(
+    lua: &'static mlua::Lua,
+    lua_file: &str,
+    keep_windows: bool,
+    windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>,
+    textures: &Arc<Mutex<HashMap<String, TextureHandle>>>,
+    scheduler: &Arc<Mutex<TaskScheduler>>,
+    pacer: &Arc<Mutex<FramePacer>>,
+) -> mlua::Result<(Thread<'static>, mlua::Value<'static>)> {
+    let thread = load_lua_coroutine(lua, lua_file)?;
+    let reload_state = luamod::call_onreload(lua).unwrap_or_else(|e| {
+        eprintln!("[LuaError] egui.onreload: {}", e);
+        mlua::Value::Nil
+    });
+    scheduler.lock().unwrap().clear();
+    pacer.lock().unwrap().reset_settings();
+    luagraphic::reset_default_compat();
+    luainput::clear_handlers(lua)?;
+    if keep_windows {
+        luamod::keep_windows_for_reload(windows);
+    } else {
+        windows.lock().unwrap().clear();
+        textures.lock().unwrap().clear();
+    }
+    Ok((thread, reload_state))
+}
+
+// winit/wgpuを使わずにcoroutineを指定フレーム数だけ進め、結果をPNGに書き出す
+// headlessではフレームレート制御で待機せず、実測のdtを渡す
+fn run_headless(
+    lua_engine: &'static LuaEngine,
+    args: &Args,
+    windows: &Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>>,
+    scheduler: &Arc<Mutex<TaskScheduler>>,
+    pacer: &Arc<Mutex<FramePacer>>,
+) -> i32 {
+    let lua_file = args.lua_file.as_ref().expect("headlessにはLuaファイルが必要");
+    if let Err(e) = std::fs::create_dir_all(&args.output) {
+        eprintln!("[headless] {}: {}", args.output, e);
+        return 1;
+    }
+    let co = match load_lua_coroutine(&lua_engine.lua, lua_file) {
+        Ok(co) => co,
+        Err(e) => {
+            eprintln!("[LuaError] {}", LuaErrorReport::new(&lua_engine.lua, None, &e));
+            return 1;
+        }
+    };
+    let mut exit_code = 0;
+    let mut frame = 0;
+    while frame < args.frames && co.status() == ThreadStatus::Resumable {
+        let frame_args = {
+            let mut pacer = pacer.lock().unwrap();
+            pacer.advance();
+            pacer.frame_args()
+        };
+        let result = if frame == 0 { co.resume::<_, ()>(()) } else { co.resume::<_, ()>(frame_args) };
+        if let Err(e) = result {
+            eprintln!("[LuaError] {}", LuaErrorReport::new(&lua_engine.lua, Some(&co), &e));
+            exit_code = 1;
+            break;
+        }
+        // タスクのエラーは報告のみ（他のタスクとスクリプトは続行）
+        for report in luatask::resume_all(scheduler, &lua_engine.lua, frame_args) {
+            eprintln!("[LuaError] {}", report);
+            exit_code = 1;
+        }
+        luamod::remove_closed_windows(windows);
+        frame += 1;
+        if args.save_all_frames {
+            if let Err(e) = save_windows_png(windows, &args.output, Some(frame)) {
+                eprintln!("[headless] {}", e);
+                return 1;
+            }
+        }
+    }
+    if !args.save_all_frames {
+        if let Err(e) = save_windows_png(windows, &args.output, None) {
+            eprintln!("[headless] {}", e);
+            return 1;
+        }
+    }
+    exit_code
+}
+
 fn main() {
     let args = Args::parse();
     let lua_engine_box = Box::new(LuaEngine::new().expect("Lua初期化失敗"));
@@ -47,11 +227,22 @@ fn main() {
     luaimage::register_lua_image(&lua_engine.lua).expect("image API登録失敗");
     luamidi::register(&lua_engine.lua).expect("midi API登録失敗");
     luagraphic::register_lua_graphic(&lua_engine.lua).expect("graphic API登録失敗");
+    let scheduler = Arc::new(Mutex::new(TaskScheduler::new()));
+    luatask::register_task(&lua_engine.lua, scheduler.clone()).expect("task API登録失敗");
     
     if args.lua_file.is_none() {
         lua_engine.repl().expect("REPL失敗");
         return;
     }
+    let windows: Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>> = Arc::new(Mutex::new(Vec::new()));
+    register_egui(&lua_engine.lua, windows.clone()).expect("egui Lua API登録失敗");
+    let pacer = Arc::new(Mutex::new(FramePacer::new(args.fps)));
+    luamod::register_frame_pacer(&lua_engine.lua, pacer.clone()).expect("フレームレートAPI登録失敗");
+    let input = Arc::new(Mutex::new(InputState::new()));
+    luainput::register_input(&lua_engine.lua, input.clone()).expect("入力API登録失敗");
+    if args.headless {
+        std::process::exit(run_headless(lua_engine, &args, &windows, &scheduler, &pacer));
+    }
     use egui_winit::State;
     use egui_wgpu::renderer::ScreenDescriptor;
     
@@ -80,80 +271,81 @@ fn main() {
         format: surface_format,
         width: size.width,
         height: size.height,
-        present_mode: egui_wgpu::wgpu::PresentMode::Immediate, // VSync OFF
+        present_mode: args.present_mode.into(), // デフォルトはImmediate (VSync OFF)
         alpha_mode: egui_wgpu::wgpu::CompositeAlphaMode::Auto,
         view_formats: vec![],
     };
     surface.configure(&device, &config);
     let mut renderer = egui_wgpu::renderer::Renderer::new(&device, surface_format, None, 1);
     let egui_ctx = egui::Context::default();
-    let windows: Arc<Mutex<Vec<Arc<Mutex<LuaWindow>>>>> = Arc::new(Mutex::new(Vec::new()));
     let textures: Arc<Mutex<HashMap<String, TextureHandle>>> = Arc::new(Mutex::new(HashMap::new()));
     let lua_file = args.lua_file.clone();
     static mut LUA_THREAD: Option<Thread<'static>> = None;
-    register_egui(&lua_engine.lua, windows.clone()).expect("egui Lua API登録失敗");
-    event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Poll;
-        if let Event::WindowEvent { event: WindowEvent::KeyboardInput { input , .. }, .. } = &event {
-            lua_engine.lua.globals().get("egui").and_then(|egui_table: mlua::Table| {
-                let handler = egui_table.get::<_, mlua::Function>(
-                    "keyhandler"
-                );
-                match handler {
-                    Ok(f) => {
-                        f.call::<(mlua::Value, mlua::Value, mlua::Value), ()>(
-                            (
-                                mlua::Value::String(lua_engine.lua.create_string(&format!("{:?}", input.state)).unwrap()),
-                                match input.virtual_keycode {
-                                    Some(key) => mlua::Value::String(lua_engine.lua.create_string(&format!("{:?}", key)).unwrap()),
-                                    None => mlua::Value::Nil,
-                                },
-                                mlua::Value::Integer(input.scancode as i64)
-                            )
-                        ).ok();
-                    },
-                    Err(_) => { /* no handler registered */ }
-                };
-                Ok(())
-            }).ok();
-        }
-        if let Event::WindowEvent { event: WindowEvent::MouseInput { state, button, .. }, .. } = &event {
-            lua_engine.lua.globals().get("egui").and_then(|egui_table: mlua::Table| {
-                let handler = egui_table.get::<_, mlua::Function>
-                    ("mousehandler"
-                );
-                match handler {
-                    Ok(f) => {
-                        f.call::<(mlua::Value, mlua::Value), ()>(
-                            (
-                                mlua::Value::String(lua_engine.lua.create_string(&format!("{:?}", state)).unwrap()),
-                                mlua::Value::String(lua_engine.lua.create_string(&format!("{:?}", button)).unwrap()),
-                            )
-                        ).ok();
-                    },
-                    Err(_) => { /* no handler registered */ }
-                };
-                Ok(())
-            }).ok();
+    // リロード直後の最初のresumeでチャンクの ... として渡す値
+    let mut reload_state: Option<mlua::Value<'static>> = None;
+    let mut watcher = match (&lua_file, args.watch) {
+        (Some(lua_file), true) => Some(ScriptWatcher::new(lua_file)),
+        _ => None,
+    };
+    let keep_windows = args.keep_windows;
+    // エラーウィンドウに表示中のLuaエラーと、Restartボタン/ファイル更新による再ロード要求
+    let mut lua_error: Option<LuaErrorReport> = None;
+    let mut reload_requested = false;
+    if let Some(ref lua_file) = lua_file {
+        match load_lua_coroutine(&lua_engine.lua, lua_file) {
+            Ok(thread) => unsafe {
+                LUA_THREAD = Some(thread);
+                reload_state = Some(mlua::Value::Nil);
+            },
+            Err(e) => {
+                let report = LuaErrorReport::new(&lua_engine.lua, None, &e);
+                eprintln!("[LuaError] {}", report);
+                lua_error = Some(report);
+            }
         }
-        if let Event::WindowEvent { event: WindowEvent::CursorMoved { position, .. }, .. } = &event {
-            lua_engine.lua.globals().get("egui").and_then(|egui_table: mlua::Table| {
-                let handler = egui_table.get::<_, mlua::Function>(
-                    "cursorhandler"
-                );
-                match handler {
-                    Ok(f) => {
-                        f.call::<(mlua::Value, mlua::Value), ()>(
-                            (
-                                mlua::Value::Integer(position.x as i64),
-                                mlua::Value::Integer(position.y as i64),
-                            )
-                        ).ok();
-                    },
-                    Err(_) => { /* no handler registered */ }
-                };
-                Ok(())
-            }).ok();
+    }
+    event_loop.run(move |event, _, control_flow| {
+        if let Event::WindowEvent { event, .. } = &event {
+            input.lock().unwrap().handle_window_event(event);
+            pacer.lock().unwrap().notify_event();
+            // 従来のコールバック（coroutine外で同期的に呼ばれる）
+            let lua = &lua_engine.lua;
+            let result = match event {
+                WindowEvent::KeyboardInput { input, .. } => luainput::call_handler(lua, "keyhandler", (
+                    format!("{:?}", input.state),
+                    input.virtual_keycode.map(luainput::key_name),
+                    input.scancode as i64,
+                )),
+                WindowEvent::MouseInput { state, button, .. } => luainput::call_handler(lua, "mousehandler", (
+                    format!("{:?}", state),
+                    format!("{:?}", button),
+                )),
+                WindowEvent::CursorMoved { position, .. } => luainput::call_handler(lua, "cursorhandler", (
+                    position.x as i64,
+                    position.y as i64,
+                )),
+                WindowEvent::CursorEntered { .. } => luainput::call_handler(lua, "cursorenterhandler", true),
+                WindowEvent::CursorLeft { .. } => luainput::call_handler(lua, "cursorenterhandler", false),
+                WindowEvent::MouseWheel { delta, .. } => {
+                    let (dx, dy, unit) = luainput::wheel_delta(delta);
+                    luainput::call_handler(lua, "wheelhandler", (dx, dy, unit))
+                }
+                WindowEvent::DroppedFile(path) => luainput::call_handler(lua, "filedrophandler",
+                    path.to_string_lossy().into_owned(),
+                ),
+                WindowEvent::HoveredFile(path) => luainput::call_handler(lua, "filehoverhandler",
+                    Some(path.to_string_lossy().into_owned()),
+                ),
+                WindowEvent::HoveredFileCancelled => luainput::call_handler(lua, "filehoverhandler", None::<String>),
+                WindowEvent::Focused(focused) => luainput::call_handler(lua, "focushandler", *focused),
+                WindowEvent::Resized(size) => luainput::call_handler(lua, "resizehandler", (size.width, size.height)),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                let report = LuaErrorReport::new(lua, None, &e);
+                eprintln!("[LuaError] {}", report);
+                lua_error = Some(report);
+            }
         }
         match &event {
             Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
@@ -169,32 +361,127 @@ fn main() {
             _ => {}
         }
         if let Event::WindowEvent { event, .. } = &event {
-            let _ = state.on_event(&egui_ctx, event);
+            if state.on_event(&egui_ctx, event).repaint {
+                window.request_redraw();
+            }
         }
         if let Event::MainEventsCleared = event {
+            // ファイルが保存されたらcoroutineを差し替える（アイドル中も確認する）
+            if let Some(watcher) = watcher.as_mut() {
+                reload_requested |= watcher.poll_changed();
+            }
+            // アイドル中（egui.setidle / egui.waitevent）はイベント・タイマー・invalidateがあるまで待機
+            let idle_wait = if reload_requested { None } else { pacer.lock().unwrap().idle_wait() };
+            if let Some(wake) = idle_wait {
+                let wake = match (wake, watcher.as_ref()) {
+                    (Some(wake), Some(watcher)) => Some(wake.min(watcher.next_check())),
+                    (wake, watcher) => wake.or(watcher.map(|watcher| watcher.next_check())),
+                };
+                *control_flow = match wake {
+                    Some(wake) => ControlFlow::WaitUntil(wake),
+                    None => ControlFlow::Wait,
+                };
+                return;
+            }
+            // 目標フレームレートに達していなければ次のフレーム時刻まで待機（入力イベントでは起床する）
+            if !pacer.lock().unwrap().begin_frame() {
+                if let Some(next) = pacer.lock().unwrap().next_frame_time() {
+                    *control_flow = ControlFlow::WaitUntil(next);
+                }
+                return;
+            }
+            let frame_args = pacer.lock().unwrap().frame_args();
+            {
+                let mut input = input.lock().unwrap();
+                if let Some(allowed) = input.take_ime_allowed_request() {
+                    window.set_ime_allowed(allowed);
+                }
+                if let Some((x, y)) = input.take_ime_position_request() {
+                    window.set_ime_position(egui_winit::winit::dpi::PhysicalPosition::new(x, y));
+                }
+            }
+            if let Some(vsync) = pacer.lock().unwrap().take_vsync_request() {
+                config.present_mode = if vsync {
+                    egui_wgpu::wgpu::PresentMode::AutoVsync
+                } else {
+                    egui_wgpu::wgpu::PresentMode::AutoNoVsync
+                };
+                if config.width > 0 && config.height > 0 {
+                    surface.configure(&device, &config);
+                }
+            }
+            // キャンバスのクリック・ドラッグを onclick/ondrag へ
+            for report in luamod::dispatch_window_events(&lua_engine.lua, &windows) {
+                eprintln!("[LuaError] {}", report);
+                lua_error = Some(report);
+            }
             // Luaコルーチンを1フレーム分進める
+            // yield()の戻り値は (dt, frame, elapsed)、(再)ロード直後の初回のみ onreload の戻り値
             unsafe {
-                if LUA_THREAD.is_none() {
-                    if let Some(ref lua_file) = lua_file {
-                        let thread = load_lua_coroutine(&lua_engine.lua, lua_file).expect("Lua coroutineロード失敗");
-                        LUA_THREAD = Some(thread);
+                if let (true, Some(ref lua_file)) = (reload_requested, &lua_file) {
+                    reload_requested = false;
+                    match reload_lua_coroutine(&lua_engine.lua, lua_file, keep_windows, &windows, &textures, &scheduler, &pacer) {
+                        Ok((thread, state)) => {
+                            eprintln!("[reload] {}", lua_file);
+                            // setvsyncで変えた画面の更新方式もコマンドラインの指定に戻す
+                            let present_mode = args.present_mode.into();
+                            if config.present_mode != present_mode {
+                                config.present_mode = present_mode;
+                                if config.width > 0 && config.height > 0 {
+                                    surface.configure(&device, &config);
+                                }
+                            }
+                            input.lock().unwrap().clear_events();
+                            LUA_THREAD = Some(thread);
+                            reload_state = Some(state);
+                            lua_error = None;
+                        },
+                        Err(e) => {
+                            let report = LuaErrorReport::new(&lua_engine.lua, None, &e);
+                            eprintln!("[LuaError] {}", report);
+                            lua_error = Some(report);
+                        }
                     }
                 }
                 if let Some(ref mut co) = LUA_THREAD {
                     if co.status() == ThreadStatus::Resumable {
-                        match co.resume::<(), ()>(()) {
+                        let first_resume = reload_state.is_some();
+                        let result = match reload_state.take() {
+                            Some(state) => co.resume::<_, ()>(state),
+                            None => co.resume::<_, ()>(frame_args),
+                        };
+                        // 残したウィンドウのうち新しいスクリプトが最初のフレームで作り直さなかったものは閉じる
+                        if first_resume {
+                            luamod::close_kept_windows(&windows);
+                        }
+                        match result {
                             Ok(_) => {},
                             Err(e) => {
-                                eprintln!("[LuaError] {}", e);
-                                // if let mlua::Error::RuntimeError(msg) = &e {
-                                //     // Luaエラー時はtracebackも含まれることが多い
-                                //     eprintln!("[LuaTraceback]\n{}", msg);
-                                // }
+                                let report = LuaErrorReport::new(&lua_engine.lua, Some(co), &e);
+                                eprintln!("[LuaError] {}", report);
+                                lua_error = Some(report);
                             }
                         };
                     }
                 }
+                // task.spawnされたタスクを進める（エラーのタスクだけ終了し、他は続行）
+                for report in luatask::resume_all(&scheduler, &lua_engine.lua, frame_args) {
+                    eprintln!("[LuaError] {}", report);
+                    lua_error = Some(report);
+                }
+            }
+            // w:close() されたウィンドウとそのテクスチャを破棄
+            let closed = luamod::remove_closed_windows(&windows);
+            if !closed.is_empty() {
+                let mut textures = textures.lock().unwrap();
+                for id in closed {
+                    textures.remove(&id);
+                }
             }
+            *control_flow = match pacer.lock().unwrap().next_frame_time() {
+                Some(next) => ControlFlow::WaitUntil(next),
+                None => ControlFlow::Poll,
+            };
             window.request_redraw();
         }
         if let Event::RedrawRequested(_) = event {
@@ -202,27 +489,121 @@ fn main() {
                 return;
             }
             let raw_input = state.take_egui_input(&window);
+            let lua = &lua_engine.lua;
+            let mut ui_errors = Vec::new();
             let full_output = egui_ctx.run(raw_input, |ctx| {
-                let windows_lock = windows.lock().unwrap();
+                // onuiの関数からegui.create_windowやウィンドウのメソッドを呼べるように、ロックを保持したまま呼ばない
+                let windows_list = windows.lock().unwrap().clone();
                 let mut textures = textures.lock().unwrap();
-                for w in windows_lock.iter() {
-                    let mut w = w.lock().unwrap();
-                    let image = ColorImage::from_rgba_unmultiplied([w.width, w.height], &w.buffer);
-                    let tex = textures.entry(w.id.clone()).or_insert_with(|| {
-                        ctx.load_texture(&w.id, image.clone(), TextureOptions::NEAREST)
-                    });
-                    tex.set(image, TextureOptions::NEAREST);
-                    let inner_response = egui::Window::new(&w.id).show(ctx, |ui| {
-                        let size = egui::Vec2::new(w.width as f32, w.height as f32);
-                        ui.image(&*tex, size);
+                for win in windows_list.iter() {
+                    let mut w = win.lock().unwrap();
+                    if !w.visible || w.closed {
+                        continue;
+                    }
+                    // 前のフレームから描画された範囲だけテクスチャへ転送する
+                    let dirty = w.take_dirty();
+                    let tex = match textures.get_mut(&w.id) {
+                        Some(tex) => {
+                            match dirty {
+                                Some((x0, y0, x1, y1)) if tex.size() == [w.width, w.height] && (x1 - x0, y1 - y0) != (w.width, w.height) => {
+                                    let region = w.region_rgba(x0, y0, x1, y1);
+                                    tex.set_partial([x0, y0], ColorImage::from_rgba_unmultiplied([x1 - x0, y1 - y0], &region), w.filter);
+                                }
+                                // 全体が変わった・サイズやフィルタが変わった場合は全体を転送
+                                Some(_) => tex.set(ColorImage::from_rgba_unmultiplied([w.width, w.height], &w.buffer), w.filter),
+                                None => {}
+                            }
+                            tex.clone()
+                        }
+                        None => {
+                            let image = ColorImage::from_rgba_unmultiplied([w.width, w.height], &w.buffer);
+                            let tex = ctx.load_texture(&w.id, image, w.filter);
+                            textures.insert(w.id.clone(), tex.clone());
+                            tex
+                        }
+                    };
+                    // タイトルが変わっても同じウィンドウとして扱うようにidを固定する
+                    let mut open = true;
+                    let mut egui_window = egui::Window::new(w.title.clone()).id(egui::Id::new(&w.id)).open(&mut open);
+                    if let Some((x, y)) = w.pending_pos.take() {
+                        egui_window = egui_window.current_pos(egui::pos2(x as f32, y as f32));
+                    }
+                    if w.fit {
+                        // 初期サイズはscale倍、以降はユーザーがリサイズしたサイズに合わせる
+                        let default_size = egui::Vec2::new(w.width as f32, w.height as f32) * w.scale;
+                        egui_window = egui_window.resizable(true).default_size(default_size);
+                    }
+                    let onui = w.onui.as_ref().and_then(|key| lua.registry_value::<mlua::Function>(key).ok());
+                    let (ui_side, fit) = (w.ui_side, w.fit);
+                    drop(w);
+                    let add_canvas = |ui: &mut egui::Ui| {
+                        let w = win.lock().unwrap();
+                        let image = egui::Image::new(&tex, w.display_size(ui.available_size()))
+                            .sense(egui::Sense::click_and_drag());
+                        if w.fit {
+                            ui.centered_and_justified(|ui| ui.add(image)).inner
+                        } else {
+                            ui.add(image)
+                        }
+                    };
+                    let inner_response = egui_window.show(ctx, |ui| match onui {
+                        Some(onui) => {
+                            let run_ui = |ui: &mut egui::Ui| {
+                                if let Err(e) = luaui::call_ui(lua, ui, &onui) {
+                                    ui_errors.push((win.clone(), LuaErrorReport::new(lua, None, &e)));
+                                }
+                            };
+                            luaui::show_with_ui(ui, ui_side, fit, run_ui, add_canvas)
+                        }
+                        None => add_canvas(ui),
                     });
-                    if let Some(window_rect) = inner_response.map(|r| r.response.rect) {
-                        let pos = window_rect.min;
+                    let mut w = win.lock().unwrap();
+                    if !open {
+                        w.close_requested = true;
+                    }
+                    if let Some(inner_response) = inner_response {
+                        let pos = inner_response.response.rect.min;
                         w.x = pos.x as i32;
                         w.y = pos.y as i32;
+                        match inner_response.inner {
+                            Some(image_response) => w.update_pointer(&image_response),
+                            None => w.hovered = false, // 折りたたみ中
+                        }
+                    }
+                }
+                // Luaエラー表示（Restartでスクリプトを再ロード、Dismissで閉じる）
+                if let Some(report) = &lua_error {
+                    let mut dismissed = false;
+                    egui::Window::new("Lua Error").collapsible(false).default_width(600.0).show(ctx, |ui| {
+                        ui.colored_label(egui::Color32::LIGHT_RED, &report.message);
+                        if let (Some((chunk, line)), Some(source)) = (&report.location, &report.source_line) {
+                            ui.separator();
+                            ui.monospace(format!("{}:{}", chunk, line));
+                            ui.code(source.trim());
+                        }
+                        if !report.traceback.is_empty() {
+                            ui.separator();
+                            egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                                ui.monospace(&report.traceback);
+                            });
+                        }
+                        ui.separator();
+                        ui.horizontal(|ui| {
+                            reload_requested |= ui.button("Restart").clicked();
+                            dismissed = ui.button("Dismiss").clicked();
+                        });
+                    });
+                    if dismissed || reload_requested {
+                        lua_error = None;
                     }
                 }
             });
+            // エラーになったonuiは毎フレーム同じエラーを出さないように解除する
+            for (win, report) in ui_errors {
+                win.lock().unwrap().onui = None;
+                eprintln!("[LuaError] {}", report);
+                lua_error = Some(report);
+            }
             let needs_repaint = full_output.repaint_after.is_zero();
             let clipped_primitives = egui_ctx.tessellate(full_output.shapes);
             let screen_desc = ScreenDescriptor {